@@ -0,0 +1,575 @@
+use crate::interpolation::is_optional_segment;
+use crate::request_processing::ID_STRATEGIES;
+use crate::types::{Config, Route};
+use crate::variable_generation::variable_order;
+use axum::http::StatusCode;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// Number of lines shown on either side of the failing line in parse errors.
+const SNIPPET_CONTEXT_LINES: usize = 2;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse(Box<ParseError>),
+    /// A `--config-dir` holding no `.yaml`, `.yml`, `.json` or `.json5` files
+    EmptyDir {
+        path: String,
+    },
+    /// A config that parses but can't be served, e.g. cyclic derived variables
+    Invalid {
+        path: String,
+        message: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub path: String,
+    /// Dotted path to the key that failed to deserialize, e.g. `routes[2].method`
+    pub key_path: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    pub snippet: Option<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "Failed to read config file '{path}': {source}")
+            }
+            ConfigError::Parse(e) => {
+                write!(f, "Failed to parse config file '{}'", e.path)?;
+                if let (Some(line), Some(column)) = (e.line, e.column) {
+                    write!(f, " at line {line}, column {column}")?;
+                }
+                if let Some(key_path) = &e.key_path {
+                    write!(f, " (key: {key_path})")?;
+                }
+                write!(f, ": {}", e.message)?;
+                if let Some(snippet) = &e.snippet {
+                    write!(f, "\n{snippet}")?;
+                }
+                Ok(())
+            }
+            ConfigError::EmptyDir { path } => {
+                write!(
+                    f,
+                    "No .yaml, .yml, .json or .json5 config files found in '{path}'"
+                )
+            }
+            ConfigError::Invalid { path, message } => {
+                write!(f, "Invalid config file '{path}': {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Parse(_) | ConfigError::EmptyDir { .. } | ConfigError::Invalid { .. } => {
+                None
+            }
+        }
+    }
+}
+
+/// Syntax a config file is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    /// JSON allowing comments, trailing commas and unquoted keys
+    Json5,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension, treating anything unknown as JSON.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            ConfigFormat::Yaml
+        } else if path.ends_with(".json5") {
+            ConfigFormat::Json5
+        } else {
+            ConfigFormat::Json
+        }
+    }
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            "json5" => Ok(ConfigFormat::Json5),
+            other => Err(format!(
+                "unknown config format '{other}' (expected yaml, json or json5)"
+            )),
+        }
+    }
+}
+
+/// Reads and parses a config file, choosing YAML, JSON or JSON5 by extension.
+pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    load_config_as(path, ConfigFormat::from_path(path))
+}
+
+/// Reads and parses a config file written in `format`, whatever its extension.
+pub fn load_config_as(path: &str, format: ConfigFormat) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+
+    let config = parse_config_as(&content, path, format)?;
+    validate_config(&config).map_err(|message| ConfigError::Invalid {
+        path: path.to_string(),
+        message,
+    })?;
+    Ok(config)
+}
+
+/// Checks what deserialization can't, such as derived variables forming a cycle.
+pub fn validate_config(config: &Config) -> Result<(), String> {
+    if let Some(case) = &config.response_case {
+        validate_response_case(case).map_err(|e| format!("response_case: {e}"))?;
+    }
+    for route in &config.routes {
+        validate_route(route)?;
+    }
+    Ok(())
+}
+
+pub fn validate_route(route: &Route) -> Result<(), String> {
+    let route_name = || format!("{} {}", route.method.to_uppercase(), route.path);
+    if let Some(variables) = &route.variables {
+        variable_order(variables).map_err(|e| format!("{}: {e}", route_name()))?;
+    }
+    let segments: Vec<&str> = route.path.split('/').collect();
+    if segments[..segments.len() - 1]
+        .iter()
+        .any(|segment| is_optional_segment(segment))
+    {
+        return Err(format!(
+            "{}: only the last path segment can be optional",
+            route_name()
+        ));
+    }
+    if let Some(format) = &route.format
+        && !matches!(format.as_str(), "json" | "ndjson")
+    {
+        return Err(format!(
+            "{}: unknown format '{format}' (expected json or ndjson)",
+            route_name()
+        ));
+    }
+    if let Some(pagination) = &route.paginate
+        && (pagination.per_page == Some(0) || pagination.max_per_page == Some(0))
+    {
+        return Err(format!(
+            "{}: paginate per_page and max_per_page must be at least 1",
+            route_name()
+        ));
+    }
+    let templates = route
+        .response
+        .iter()
+        .chain(route.responses_by_accept.iter().flat_map(|t| t.values()))
+        .chain(route.param_cases.iter().flat_map(|t| t.values()));
+    for template in templates {
+        if let Some(encoded) = &template.body_base64
+            && let Err(e) = BASE64_STANDARD.decode(encoded)
+        {
+            return Err(format!("{}: invalid body_base64: {e}", route_name()));
+        }
+    }
+    if let Some(weights) = &route.status_weights {
+        if weights
+            .values()
+            .any(|weight| !weight.is_finite() || *weight < 0.0)
+        {
+            return Err(format!(
+                "{}: status_weights must be non-negative numbers",
+                route_name()
+            ));
+        }
+        if let Some(status) = weights.keys().find(|s| StatusCode::from_u16(**s).is_err()) {
+            return Err(format!(
+                "{}: invalid status {status} in status_weights",
+                route_name()
+            ));
+        }
+        if weights.values().sum::<f64>() <= 0.0 {
+            return Err(format!(
+                "{}: status_weights need at least one positive weight",
+                route_name()
+            ));
+        }
+    }
+    if route.method.eq_ignore_ascii_case("POST")
+        && route.stores_objects()
+        && !route.has_id_source()
+        && route.lua_script.is_none()
+    {
+        warn!(
+            "{}: stores objects but has no `id` variable or `id_strategy`; \
+             they will be stored under random UUIDs",
+            route_name()
+        );
+    }
+    route
+        .field_regexes()
+        .map_err(|e| format!("{}: {e}", route_name()))?;
+    if let Some(Err(e)) = route.schema_validator() {
+        return Err(format!("{}: invalid object_schema: {e}", route_name()));
+    }
+    if let Some(case) = &route.response_case {
+        validate_response_case(case).map_err(|e| format!("{}: {e}", route_name()))?;
+    }
+    if let Some(strategy) = &route.id_strategy
+        && !ID_STRATEGIES.contains(&strategy.as_str())
+    {
+        return Err(format!(
+            "{}: unknown id_strategy '{strategy}' (expected one of: {})",
+            route_name(),
+            ID_STRATEGIES.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn validate_response_case(case: &str) -> Result<(), String> {
+    match case {
+        "camel" | "snake" | "pascal" => Ok(()),
+        other => Err(format!(
+            "unknown response_case '{other}' (expected camel, snake or pascal)"
+        )),
+    }
+}
+
+/// Merges the file named by `defaults_file` into the config's `defaults`. Keys the config
+/// sets itself win, so a shared file can be overridden per environment. A relative path is
+/// resolved against `base_dir`, the directory the config was loaded from.
+pub fn load_defaults_file(mut config: Config, base_dir: &Path) -> Result<Config, ConfigError> {
+    let Some(file) = &config.defaults_file else {
+        return Ok(config);
+    };
+    let path = base_dir.join(file).to_string_lossy().into_owned();
+    let content = fs::read_to_string(&path).map_err(|source| ConfigError::Io {
+        path: path.clone(),
+        source,
+    })?;
+
+    let defaults: HashMap<String, Value> =
+        parse_as(&content, &path, ConfigFormat::from_path(&path))?;
+    let merged = config.defaults.get_or_insert_with(Default::default);
+    for (key, value) in defaults {
+        merged.entry(key).or_insert(value);
+    }
+    Ok(config)
+}
+
+/// Loads every `.yaml`, `.yml`, `.json` and `.json5` file in `dir`, in filename order, and
+/// merges them into one config. Routes keep that order, so on conflicts the
+/// earlier file wins; conflicting routes, defaults and settings are warned about.
+pub fn load_config_dir(dir: &str) -> Result<Config, ConfigError> {
+    let io_error = |source| ConfigError::Io {
+        path: dir.to_string(),
+        source,
+    };
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        let is_config = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "yaml" | "yml" | "json" | "json5"));
+        if is_config && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let mut merged: Option<Config> = None;
+    for file in &files {
+        let config = load_config(&file.to_string_lossy())?;
+        match &mut merged {
+            None => merged = Some(config),
+            Some(merged) => merge_config(merged, config, file),
+        }
+    }
+
+    merged.ok_or_else(|| ConfigError::EmptyDir {
+        path: dir.to_string(),
+    })
+}
+
+fn merge_config(merged: &mut Config, config: Config, file: &Path) {
+    let file = file.display();
+
+    for route in config.routes {
+        if merged
+            .routes
+            .iter()
+            .any(|r| r.path == route.path && r.method.eq_ignore_ascii_case(&route.method))
+        {
+            warn!(
+                "{file}: route {} {} is already defined by an earlier file",
+                route.method.to_uppercase(),
+                route.path
+            );
+        }
+        merged.routes.push(route);
+    }
+
+    if let Some(defaults) = config.defaults {
+        let merged_defaults = merged.defaults.get_or_insert_with(Default::default);
+        for (key, value) in defaults {
+            match merged_defaults.get(&key) {
+                Some(existing) if *existing != value => {
+                    warn!(
+                        "{file}: default '{key}' conflicts with an earlier file; keeping {existing}"
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    merged_defaults.insert(key, value);
+                }
+            }
+        }
+    }
+
+    if let Some(context) = config.context {
+        let merged_context = merged.context.get_or_insert_with(Default::default);
+        for (key, value) in context {
+            match merged_context.get(&key) {
+                Some(existing) if *existing != value => {
+                    warn!(
+                        "{file}: context '{key}' conflicts with an earlier file; keeping {existing}"
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    merged_context.insert(key, value);
+                }
+            }
+        }
+    }
+
+    if let Some(global_headers) = config.global_headers {
+        let merged_headers = merged.global_headers.get_or_insert_with(Default::default);
+        for (name, value) in global_headers {
+            match merged_headers.get(&name) {
+                Some(existing) if *existing != value => {
+                    warn!(
+                        "{file}: global header '{name}' conflicts with an earlier file; keeping {existing}"
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    merged_headers.insert(name, value);
+                }
+            }
+        }
+    }
+
+    if let Some(seed) = config.seed {
+        merged.seed.get_or_insert_with(Vec::new).extend(seed);
+    }
+
+    merge_setting(
+        &mut merged.proxy_fallback,
+        config.proxy_fallback,
+        "proxy_fallback",
+        &file,
+    );
+    merge_setting(
+        &mut merged.resolve_stored_references,
+        config.resolve_stored_references,
+        "resolve_stored_references",
+        &file,
+    );
+    merge_setting(
+        &mut merged.null_missing_fields,
+        config.null_missing_fields,
+        "null_missing_fields",
+        &file,
+    );
+    merge_setting(
+        &mut merged.sequence_objects,
+        config.sequence_objects,
+        "sequence_objects",
+        &file,
+    );
+    merge_setting(
+        &mut merged.clear_state,
+        config.clear_state,
+        "clear_state",
+        &file,
+    );
+    merge_setting(
+        &mut merged.strict_methods,
+        config.strict_methods,
+        "strict_methods",
+        &file,
+    );
+    merge_setting(
+        &mut merged.idempotency_ttl_secs,
+        config.idempotency_ttl_secs,
+        "idempotency_ttl_secs",
+        &file,
+    );
+    merge_setting(
+        &mut merged.response_case,
+        config.response_case,
+        "response_case",
+        &file,
+    );
+    merge_setting(&mut merged.etag, config.etag, "etag", &file);
+    merge_setting(
+        &mut merged.defaults_file,
+        config.defaults_file,
+        "defaults_file",
+        &file,
+    );
+}
+
+/// Keeps the first file's value for a top-level setting, warning when a later one differs.
+fn merge_setting<T>(
+    merged: &mut Option<T>,
+    value: Option<T>,
+    name: &str,
+    file: &impl fmt::Display,
+) {
+    match (merged.as_ref(), value) {
+        (None, value) => *merged = value,
+        (Some(_), None) => {}
+        (Some(_), Some(_)) => {
+            warn!("{file}: '{name}' is already set by an earlier file; ignoring it");
+        }
+    }
+}
+
+pub fn parse_config(content: &str, path: &str) -> Result<Config, ConfigError> {
+    parse_config_as(content, path, ConfigFormat::from_path(path))
+}
+
+pub fn parse_config_as(
+    content: &str,
+    path: &str,
+    format: ConfigFormat,
+) -> Result<Config, ConfigError> {
+    parse_as(content, path, format)
+}
+
+fn parse_as<T: DeserializeOwned>(
+    content: &str,
+    path: &str,
+    format: ConfigFormat,
+) -> Result<T, ConfigError> {
+    match format {
+        ConfigFormat::Json5 => {
+            let json5_error = |key_path: String, e: json5::Error| {
+                let json5::Error::Message { msg, location } = e;
+                parse_error(
+                    path,
+                    content,
+                    key_path,
+                    location.as_ref().map(|l| l.line),
+                    location.as_ref().map(|l| l.column),
+                    msg,
+                )
+            };
+            let mut deserializer = json5::Deserializer::from_str(content)
+                .map_err(|e| json5_error(".".to_string(), e))?;
+            serde_path_to_error::deserialize(&mut deserializer)
+                .map_err(|e| json5_error(e.path().to_string(), e.into_inner()))
+        }
+        ConfigFormat::Yaml => {
+            let deserializer = serde_yaml::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(deserializer).map_err(|e| {
+                let location = e.inner().location();
+                parse_error(
+                    path,
+                    content,
+                    e.path().to_string(),
+                    location.as_ref().map(|l| l.line()),
+                    location.as_ref().map(|l| l.column()),
+                    e.inner().to_string(),
+                )
+            })
+        }
+        ConfigFormat::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+                let (line, column) = (e.inner().line(), e.inner().column());
+                parse_error(
+                    path,
+                    content,
+                    e.path().to_string(),
+                    (line > 0).then_some(line),
+                    (line > 0).then_some(column),
+                    e.inner().to_string(),
+                )
+            })
+        }
+    }
+}
+
+fn parse_error(
+    path: &str,
+    content: &str,
+    key_path: String,
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+) -> ConfigError {
+    ConfigError::Parse(Box::new(ParseError {
+        path: path.to_string(),
+        // serde_path_to_error reports "." when the failure is at the document root
+        key_path: (key_path != ".").then_some(key_path),
+        line,
+        column,
+        message,
+        snippet: line.map(|line| snippet(content, line, column.unwrap_or(1))),
+    }))
+}
+
+/// Renders the lines around `line` (1-based) with a caret under `column`.
+fn snippet(content: &str, line: usize, column: usize) -> String {
+    let lines: Vec<&str> = content.lines().collect();
+    let first = line.saturating_sub(SNIPPET_CONTEXT_LINES).max(1);
+    let last = (line + SNIPPET_CONTEXT_LINES).min(lines.len());
+    let width = last.to_string().len();
+
+    let mut out = Vec::new();
+    for number in first..=last {
+        let text = lines.get(number - 1).copied().unwrap_or("");
+        let marker = if number == line { '>' } else { ' ' };
+        out.push(format!("{marker} {number:>width$} | {text}"));
+        if number == line {
+            let padding = " ".repeat(column.saturating_sub(1));
+            out.push(format!("  {:>width$} | {padding}^", ""));
+        }
+    }
+
+    out.join("\n")
+}
@@ -1,3 +1,4 @@
+use crate::interpolation::replace_simple_placeholders;
 use crate::types::StoredObject;
 use serde_json::{Value, json};
 use std::collections::HashMap;
@@ -6,10 +7,14 @@ use std::sync::{Arc, RwLock};
 pub fn resolve_cross_references(
     value: &Value,
     objects: &Arc<RwLock<HashMap<String, Vec<StoredObject>>>>,
+    defaults: &Option<HashMap<String, Value>>,
+    null_missing_fields: bool,
 ) -> Value {
     match value {
         Value::String(s) => {
-            if let Some(resolved) = resolve_reference_string(s, objects) {
+            if let Some(resolved) =
+                resolve_reference_string(s, objects, defaults, null_missing_fields)
+            {
                 return resolved;
             }
 
@@ -18,14 +23,17 @@ pub fn resolve_cross_references(
         Value::Object(obj) => {
             let mut new_obj = serde_json::Map::new();
             for (k, v) in obj {
-                new_obj.insert(k.clone(), resolve_cross_references(v, objects));
+                new_obj.insert(
+                    k.clone(),
+                    resolve_cross_references(v, objects, defaults, null_missing_fields),
+                );
             }
             Value::Object(new_obj)
         }
         Value::Array(arr) => {
             let new_arr: Vec<Value> = arr
                 .iter()
-                .map(|v| resolve_cross_references(v, objects))
+                .map(|v| resolve_cross_references(v, objects, defaults, null_missing_fields))
                 .collect();
             Value::Array(new_arr)
         }
@@ -33,12 +41,107 @@ pub fn resolve_cross_references(
     }
 }
 
-fn resolve_reference_string(
+/// Expands `{"for_each": {"source": "objects.type", "as": "item", "template": ...}}` nodes
+/// into an array holding one rendered `template` per source element, where `{item}` and
+/// `{item.field}` placeholders refer to that element.
+pub fn expand_for_each(
+    value: &Value,
+    objects: &Arc<RwLock<HashMap<String, Vec<StoredObject>>>>,
+    defaults: &Option<HashMap<String, Value>>,
+    null_missing_fields: bool,
+) -> Value {
+    match value {
+        Value::Object(obj) => {
+            if obj.len() == 1
+                && let Some(spec) = obj.get("for_each")
+                && let (Some(source), Some(template)) = (
+                    spec.get("source").and_then(Value::as_str),
+                    spec.get("template"),
+                )
+            {
+                let alias = spec.get("as").and_then(Value::as_str).unwrap_or("item");
+                let reference = format!("{{{source}}}");
+                let items = match resolve_reference_string(
+                    &reference,
+                    objects,
+                    defaults,
+                    null_missing_fields,
+                ) {
+                    Some(Value::Array(items)) => items,
+                    _ => Vec::new(),
+                };
+
+                let rendered: Vec<Value> = items
+                    .iter()
+                    .map(|item| {
+                        let shaped = render_item(template, alias, item);
+                        expand_for_each(&shaped, objects, defaults, null_missing_fields)
+                    })
+                    .collect();
+                return Value::Array(rendered);
+            }
+
+            Value::Object(
+                obj.iter()
+                    .map(|(k, v)| {
+                        (
+                            k.clone(),
+                            expand_for_each(v, objects, defaults, null_missing_fields),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        Value::Array(arr) => Value::Array(
+            arr.iter()
+                .map(|v| expand_for_each(v, objects, defaults, null_missing_fields))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+fn render_item(template: &Value, alias: &str, item: &Value) -> Value {
+    replace_simple_placeholders(template, |placeholder| {
+        if placeholder == alias {
+            return Some(item.clone());
+        }
+        let field_path = placeholder.strip_prefix(alias)?.strip_prefix('.')?;
+        extract_field_value(item, field_path)
+    })
+}
+
+/// Resolves a single `{objects...}` reference string against the object store.
+///
+/// References to a type with no stored objects still resolve: list references to `[]`,
+/// `.first`/`.last` to `null` and `[id].field` lookups to the `defaults` entry for that
+/// field, or `null`.
+///
+/// `[id].field` lookups fall back the same way when no object has that id, since the id
+/// often comes from the request path, and when the object lacks the field.
+///
+/// With `null_missing_fields`, `{objects.type.field}` projections hold `null` for objects
+/// lacking the field, so they stay aligned with the object list.
+pub fn resolve_reference_string(
     s: &str,
     objects: &Arc<RwLock<HashMap<String, Vec<StoredObject>>>>,
+    defaults: &Option<HashMap<String, Value>>,
+    null_missing_fields: bool,
 ) -> Option<Value> {
     let objects_guard = objects.read().unwrap();
 
+    if let Some(grouped) = resolve_group_by(s, &objects_guard) {
+        return Some(grouped);
+    }
+
+    if let Some(positioned) = resolve_position(s, &objects_guard, defaults) {
+        return Some(positioned);
+    }
+
+    if let Some(missing) = resolve_missing_type(s, &objects_guard, defaults) {
+        return Some(missing);
+    }
+
     if s.starts_with("{objects.") && s.ends_with('}') {
         let object_type = &s[9..s.len() - 1];
         if let Some(objects_list) = objects_guard.get(object_type) {
@@ -57,7 +160,14 @@ fn resolve_reference_string(
             if let Some(objects_list) = objects_guard.get(object_type) {
                 let values: Vec<Value> = objects_list
                     .iter()
-                    .filter_map(|obj| extract_field_value(&obj.data, &field_path))
+                    .filter_map(|obj| {
+                        let value = extract_field_value(&obj.data, &field_path);
+                        if null_missing_fields {
+                            value.or(Some(Value::Null))
+                        } else {
+                            value
+                        }
+                    })
                     .collect();
                 return Some(json!(values));
             }
@@ -70,29 +180,31 @@ fn resolve_reference_string(
             let object_type = &content[..bracket_pos];
             let id = &content[bracket_pos + 1..];
 
-            if let Some(objects_list) = objects_guard.get(object_type) {
-                if let Some(obj) = objects_list.iter().find(|o| o.id == id) {
-                    return Some(obj.data.clone());
-                }
+            if let Some(objects_list) = objects_guard.get(object_type)
+                && let Some(obj) = objects_list.iter().find(|o| o.id == id)
+            {
+                return Some(obj.data.clone());
             }
         }
     }
 
     if s.starts_with("{objects.") && s.contains('[') && s.contains("].") && s.ends_with('}') {
         let content = &s[9..s.len() - 1];
-        if let Some(bracket_pos) = content.find('[') {
-            if let Some(close_bracket) = content.find(']') {
-                let object_type = &content[..bracket_pos];
-                let id = &content[bracket_pos + 1..close_bracket];
-                let field_path = &content[close_bracket + 2..];
-
-                if let Some(objects_list) = objects_guard.get(object_type) {
-                    if let Some(obj) = objects_list.iter().find(|o| o.id == id) {
-                        if let Some(field_value) = extract_field_value(&obj.data, field_path) {
-                            return Some(field_value);
-                        }
-                    }
-                }
+        if let Some(bracket_pos) = content.find('[')
+            && let Some(close_bracket) = content.find(']')
+        {
+            let object_type = &content[..bracket_pos];
+            let id = &content[bracket_pos + 1..close_bracket];
+            let field_path = &content[close_bracket + 2..];
+
+            if let Some(objects_list) = objects_guard.get(object_type) {
+                let field_value = objects_list
+                    .iter()
+                    .find(|o| o.id == id)
+                    .and_then(|obj| extract_field_value(&obj.data, field_path));
+                return Some(
+                    field_value.unwrap_or_else(|| projection_default(field_path, defaults)),
+                );
             }
         }
     }
@@ -100,6 +212,120 @@ fn resolve_reference_string(
     None
 }
 
+/// Resolves `{objects.type.first}` and `{objects.type.last}` to the oldest or newest stored
+/// object of a type, and `{objects.type.last.field}` to one of its fields. Without such an
+/// object, or when it lacks the field, the result is the field's `defaults` entry or `null`.
+///
+/// Stored fields take precedence: when any object of the type has a field literally named
+/// `first` or `last`, the reference is left to the ordinary field projection, so
+/// `{objects.users.last}` keeps listing last names.
+fn resolve_position(
+    s: &str,
+    objects: &HashMap<String, Vec<StoredObject>>,
+    defaults: &Option<HashMap<String, Value>>,
+) -> Option<Value> {
+    let content = s.strip_prefix("{objects.")?.strip_suffix('}')?;
+    let mut parts = content.splitn(3, '.');
+    let (object_type, position) = (parts.next()?, parts.next()?);
+    let field_path = parts.next();
+    if object_type.contains(['[', ' ']) {
+        return None;
+    }
+
+    let list = objects
+        .get(object_type)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let object = match position {
+        "first" => list.first(),
+        "last" => list.last(),
+        _ => return None,
+    };
+    if list.iter().any(|obj| obj.data.get(position).is_some()) {
+        return None;
+    }
+
+    let Some(field_path) = field_path else {
+        return Some(object.map_or(Value::Null, |obj| obj.data.clone()));
+    };
+    object
+        .and_then(|obj| extract_field_value(&obj.data, field_path))
+        .or_else(|| {
+            let field = field_path.rsplit('.').next()?;
+            defaults.as_ref()?.get(field).cloned()
+        })
+        .or(Some(Value::Null))
+}
+
+/// Resolves references to an object type that has nothing stored yet.
+fn resolve_missing_type(
+    s: &str,
+    objects: &HashMap<String, Vec<StoredObject>>,
+    defaults: &Option<HashMap<String, Value>>,
+) -> Option<Value> {
+    let content = s.strip_prefix("{objects.")?.strip_suffix('}')?;
+    if content.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let type_end = content.find(['.', '[']).unwrap_or(content.len());
+    let object_type = &content[..type_end];
+    if object_type.is_empty()
+        || objects
+            .get(object_type)
+            .is_some_and(|list| !list.is_empty())
+    {
+        return None;
+    }
+
+    let rest = &content[type_end..];
+    let Some(lookup) = rest.strip_prefix('[') else {
+        // `{objects.type}` and `{objects.type.field}` are lists
+        return Some(json!([]));
+    };
+
+    // Only field projections get a fallback; whole-object lookups stay unresolved
+    let (_, field_path) = lookup.split_once("].")?;
+    Some(projection_default(field_path, defaults))
+}
+
+/// The `defaults` entry for the last field of `field_path`, or `null` without one.
+fn projection_default(field_path: &str, defaults: &Option<HashMap<String, Value>>) -> Value {
+    let field = field_path.rsplit('.').next().unwrap_or(field_path);
+    defaults
+        .as_ref()
+        .and_then(|d| d.get(field).cloned())
+        .unwrap_or(Value::Null)
+}
+
+/// Resolves `{objects.type group_by field count}` into a map of field value to object count.
+fn resolve_group_by(s: &str, objects: &HashMap<String, Vec<StoredObject>>) -> Option<Value> {
+    let content = s.strip_prefix("{objects.")?.strip_suffix('}')?;
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+
+    // `count` is the only reducer; anything else is left unresolved even when there are
+    // no objects to reduce
+    let [object_type, "group_by", field_path, "count"] = tokens.as_slice() else {
+        return None;
+    };
+
+    let mut groups = serde_json::Map::new();
+    for obj in objects.get(*object_type).into_iter().flatten() {
+        let Some(key) = extract_field_value(&obj.data, field_path) else {
+            continue;
+        };
+        let key = match key {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+
+        let count = groups.entry(key).or_insert(json!(0));
+        *count = json!(count.as_u64().unwrap_or(0) + 1);
+    }
+
+    Some(Value::Object(groups))
+}
+
 fn extract_field_value(data: &Value, field_path: &str) -> Option<Value> {
     let parts: Vec<&str> = field_path.split('.').collect();
     let mut current = data;
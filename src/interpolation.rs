@@ -1,49 +1,249 @@
 use serde_json::{Value, json};
 use std::collections::HashMap;
 
+/// Replaces `{payload.field}` with the payload's field, or its `defaults` entry when the
+/// payload lacks it. `{payload.field.length}` is the size of an array, string or object.
 pub fn interpolate_payload(
     template: &Value,
     payload: &Value,
     defaults: &Option<HashMap<String, Value>>,
 ) -> Value {
+    let lookup = |field_name: &str| {
+        payload
+            .as_object()
+            .and_then(|payload_obj| payload_obj.get(field_name))
+            .or_else(|| defaults.as_ref()?.get(field_name))
+    };
+
     replace_simple_placeholders(template, |placeholder| {
-        if let Some(field_name) = placeholder.strip_prefix("payload.") {
-            if let Some(payload_obj) = payload.as_object() {
-                if let Some(value) = payload_obj.get(field_name) {
-                    return Some(value.clone());
-                }
-            }
+        let field_name = placeholder.strip_prefix("payload.")?;
+        if let Some(value) = lookup(field_name) {
+            return Some(value.clone());
+        }
 
-            if let Some(defaults) = defaults {
-                if let Some(default_value) = defaults.get(field_name) {
-                    return Some(default_value.clone());
-                }
-            }
+        let sized = lookup(field_name.strip_suffix(".length")?)?;
+        let length = match sized {
+            Value::Array(items) => items.len(),
+            Value::String(s) => s.chars().count(),
+            Value::Object(map) => map.len(),
+            _ => return None,
+        };
+        Some(json!(length))
+    })
+}
+
+/// Resolves `{payload.field ? a : b}` to `a` when the payload field (or its default) is
+/// truthy and to `b` otherwise. Branches are quoted strings, JSON literals such as numbers
+/// and booleans, or `payload.field` references; anything else is taken as plain text.
+/// Without a payload every condition is false.
+pub fn resolve_conditionals(
+    template: &Value,
+    payload: Option<&Value>,
+    defaults: &Option<HashMap<String, Value>>,
+) -> Value {
+    let lookup = |reference: &str| {
+        let field_path = reference.strip_prefix("payload.")?;
+        let found = field_path
+            .split('.')
+            .try_fold(payload?, |current, part| current.get(part));
+        match found {
+            Some(value) => Some(value.clone()),
+            None => defaults.as_ref()?.get(field_path).cloned(),
+        }
+    };
+
+    replace_simple_placeholders(template, |placeholder| {
+        let (condition, when_true, when_false) = split_ternary(placeholder)?;
+        if !condition.starts_with("payload.") {
+            return None;
         }
+        let branch = if lookup(condition).is_some_and(|value| is_truthy(&value)) {
+            when_true
+        } else {
+            when_false
+        };
 
-        None
+        let quoted = branch.len() >= 2
+            && (branch.starts_with('"') && branch.ends_with('"')
+                || branch.starts_with('\'') && branch.ends_with('\''));
+        if quoted {
+            return Some(json!(branch[1..branch.len() - 1]));
+        }
+        if branch.starts_with("payload.") {
+            return Some(lookup(branch).unwrap_or(Value::Null));
+        }
+        Some(serde_json::from_str(branch).unwrap_or_else(|_| json!(branch)))
     })
 }
 
+/// Splits `condition ? a : b` at the first `?` and the following `:` outside quotes.
+fn split_ternary(placeholder: &str) -> Option<(&str, &str, &str)> {
+    let mut quote = None;
+    let mut question = None;
+    for (i, c) in placeholder.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            ('?', None) if question.is_none() => question = Some(i),
+            (':', None) if question.is_some() => {
+                let question = question?;
+                return Some((
+                    placeholder[..question].trim(),
+                    placeholder[question + 1..i].trim(),
+                    placeholder[i + 1..].trim(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `null`, `false`, `0`, `""` and empty arrays and objects are false; everything else is true.
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+/// True for an optional `{name?}` segment, which a path may omit when it is last.
+pub fn is_optional_segment(segment: &str) -> bool {
+    segment.starts_with('{') && segment.ends_with("?}") && segment.matches('{').count() == 1
+}
+
 pub fn extract_path_parameters(pattern: &str, path: &str) -> HashMap<String, String> {
     let mut params = HashMap::new();
-    let pattern_parts: Vec<&str> = pattern.split('/').collect();
+    let mut pattern_parts: Vec<&str> = pattern.split('/').collect();
     let path_parts: Vec<&str> = path.split('/').collect();
 
+    // A trailing `{name?}` may be left off, leaving its parameter unset
+    if pattern_parts.len() == path_parts.len() + 1
+        && pattern_parts
+            .last()
+            .is_some_and(|part| is_optional_segment(part))
+    {
+        pattern_parts.pop();
+    }
+
     if pattern_parts.len() != path_parts.len() {
         return params;
     }
 
     for (pattern_part, path_part) in pattern_parts.iter().zip(path_parts.iter()) {
-        if pattern_part.starts_with('{') && pattern_part.ends_with('}') {
-            let param_name = &pattern_part[1..pattern_part.len() - 1];
+        if pattern_part.starts_with('{')
+            && pattern_part.ends_with('}')
+            && pattern_part.matches('{').count() == 1
+        {
+            let param_name = pattern_part[1..pattern_part.len() - 1].trim_end_matches('?');
             params.insert(param_name.to_string(), path_part.to_string());
+        } else if pattern_part.contains('{')
+            && let Some(captures) = match_segment(pattern_part, path_part)
+        {
+            params.extend(captures);
         }
     }
 
     params
 }
 
+/// Matches a path segment against a pattern with parameters inside it, such as
+/// `{lat}-{lng}`. Each parameter captures at least one character, up to the first
+/// occurrence of the literal text that follows it.
+pub fn match_segment(pattern: &str, segment: &str) -> Option<Vec<(String, String)>> {
+    let mut captures = Vec::new();
+    let mut pattern = pattern;
+    let mut rest = segment;
+
+    while let Some(open) = pattern.find('{') {
+        rest = rest.strip_prefix(&pattern[..open])?;
+        let close = open + pattern[open..].find('}')?;
+        let name = &pattern[open + 1..close];
+        pattern = &pattern[close + 1..];
+
+        let literal = &pattern[..pattern.find('{').unwrap_or(pattern.len())];
+        let value_len = if pattern.is_empty() {
+            rest.len()
+        } else if literal.is_empty() {
+            // Adjacent parameters have no separator to split on
+            return None;
+        } else {
+            rest.get(1..)?.find(literal)? + 1
+        };
+        if value_len == 0 {
+            return None;
+        }
+
+        captures.push((name.to_string(), rest[..value_len].to_string()));
+        rest = &rest[value_len..];
+    }
+
+    (pattern == rest).then_some(captures)
+}
+
+/// Parses a query string into values keyed by name, keeping every occurrence: a key seen
+/// once maps to a string and a repeated key (`?tag=a&tag=b`) to an array of strings.
+pub fn parse_query(query: Option<&str>) -> HashMap<String, Value> {
+    let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        grouped
+            .entry(key.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+
+    grouped
+        .into_iter()
+        .map(|(key, mut values)| {
+            let value = if values.len() == 1 {
+                json!(values.remove(0))
+            } else {
+                json!(values)
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// Replaces `{query.name}` with the query value (an array for repeated keys) and
+/// `{query.name.N}` with the N-th occurrence of the key.
+pub fn replace_query_parameters(value: &Value, query: &HashMap<String, Value>) -> Value {
+    replace_simple_placeholders(value, |placeholder| {
+        let name = placeholder.strip_prefix("query.")?;
+        if let Some(found) = query.get(name) {
+            return Some(found.clone());
+        }
+
+        let (name, index) = name.rsplit_once('.')?;
+        let index: usize = index.parse().ok()?;
+        match query.get(name)? {
+            Value::Array(values) => values.get(index).cloned(),
+            single if index == 0 => Some(single.clone()),
+            _ => None,
+        }
+    })
+}
+
+/// Replaces `{context.name}` with the named value from the config's shared `context`.
+pub fn replace_context_values(value: &Value, context: &HashMap<String, Value>) -> Value {
+    replace_simple_placeholders(value, |placeholder| {
+        context.get(placeholder.strip_prefix("context.")?).cloned()
+    })
+}
+
+/// Replaces `{env.NAME}` with the environment variable's value as it is right now.
+/// Unset or non-Unicode variables leave the placeholder untouched.
+pub fn replace_env_values(value: &Value) -> Value {
+    replace_simple_placeholders(value, |placeholder| {
+        let name = placeholder.strip_prefix("env.")?;
+        std::env::var(name).ok().map(Value::String)
+    })
+}
+
 pub fn replace_simple_placeholders<F>(value: &Value, resolver: F) -> Value
 where
     F: Fn(&str) -> Option<Value> + Copy,
@@ -0,0 +1,41 @@
+//! Nugget is a dynamic HTTP stub server with cross-references and Lua scripting.
+//!
+//! The binary is a thin wrapper around this library, so the server can also be
+//! embedded in a test harness with a [`Config`] built in Rust:
+//!
+//! ```no_run
+//! use nugget::{AppState, Config, build_router};
+//!
+//! # async fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+//! let app = build_router(AppState::new(config));
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+//! axum::serve(listener, app).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod config;
+pub mod cross_references;
+pub mod interpolation;
+pub mod listener;
+pub mod lua_engine;
+pub mod multipart;
+pub mod openapi;
+pub mod proxy;
+pub mod request_processing;
+pub mod route_table;
+pub mod server;
+pub mod types;
+pub mod variable_generation;
+
+pub use config::{
+    ConfigError, ConfigFormat, load_config, load_config_as, load_config_dir, load_defaults_file,
+};
+pub use request_processing::{find_matching_route, process_response};
+pub use server::build_router;
+pub use types::{
+    AppState, AsyncJobConfig, ClearStateConfig, Config, DegradedConfig, Expectation, FaultConfig,
+    FaultHeader, FlakyConfig, NumericCondition, PaginationConfig, PreviewRequest, RecordedRequest,
+    ReplayRequest, ResponseTemplate, Route, SeedObject, ServerOptions, StatusStep, StoredObject,
+    StreamConfig, VariableConfig,
+};
@@ -1,7 +1,14 @@
+use crate::cross_references::resolve_reference_string;
 use crate::types::{AppState, LuaRequestContext};
 use mlua::{Lua, LuaSerdeExt, Value as LuaValue};
-use serde_json::Value;
+use serde_json::{Value, json};
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, trace, warn};
+
+/// Key marking a table built by the Lua `response(status, body, headers)` helper, so its
+/// status is never confused with a `status` field of an ordinary returned body.
+pub const RESPONSE_MARKER: &str = "__nugget_response";
 
 pub async fn execute_lua_script(
     script: &str,
@@ -30,7 +37,7 @@ pub async fn execute_lua_script(
         .create_function(move |lua, key: String| {
             let state_guard = state_arc.read().unwrap();
             match state_guard.get(&key) {
-                Some(value) => lua.to_value(value),
+                Some(value) => json_to_lua(lua, value),
                 None => Ok(LuaValue::Nil),
             }
         })
@@ -58,15 +65,96 @@ pub async fn execute_lua_script(
         .set("state", state_table)
         .map_err(|e| e.to_string())?;
 
-    let objects_guard = state.objects.read().unwrap();
-    let mut lua_objects: HashMap<String, Vec<Value>> = HashMap::new();
+    let defaults = state.config.defaults.clone().unwrap_or_default();
+    let defaults_table = json_to_lua(&lua, &json!(defaults)).map_err(|e| e.to_string())?;
+    request_table
+        .set("defaults", defaults_table)
+        .map_err(|e| e.to_string())?;
+
+    let defaults_get = lua
+        .create_function(move |lua, key: String| match defaults.get(&key) {
+            Some(value) => json_to_lua(lua, value),
+            None => Ok(LuaValue::Nil),
+        })
+        .map_err(|e| e.to_string())?;
+    lua.globals()
+        .set("defaults", defaults_get)
+        .map_err(|e| e.to_string())?;
+
+    let response = lua
+        .create_function(
+            |lua, (status, body, headers): (u16, LuaValue, Option<LuaValue>)| {
+                let response = lua.create_table()?;
+                response.set(RESPONSE_MARKER, true)?;
+                response.set("status", status)?;
+                response.set("body", body)?;
+                response.set("headers", headers)?;
+                Ok(response)
+            },
+        )
+        .map_err(|e| e.to_string())?;
+    lua.globals()
+        .set("response", response)
+        .map_err(|e| e.to_string())?;
 
-    for (object_type, stored_objects) in objects_guard.iter() {
+    let now = lua
+        .create_function(|_, ()| {
+            let elapsed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            Ok(elapsed.as_secs_f64())
+        })
+        .map_err(|e| e.to_string())?;
+    lua.globals().set("now", now).map_err(|e| e.to_string())?;
+
+    let script_path = request_context.path.clone();
+    let log = lua
+        .create_function(move |_, (level, message): (String, String)| {
+            match level.to_ascii_lowercase().as_str() {
+                "error" => error!("[lua {script_path}] {message}"),
+                "warn" | "warning" => warn!("[lua {script_path}] {message}"),
+                "info" => info!("[lua {script_path}] {message}"),
+                "debug" => debug!("[lua {script_path}] {message}"),
+                "trace" => trace!("[lua {script_path}] {message}"),
+                other => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "unknown log level '{other}' (expected error, warn, info, debug or trace)"
+                    )));
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+    lua.globals().set("log", log).map_err(|e| e.to_string())?;
+
+    let objects_arc = state.objects.clone();
+    let config_defaults = state.config.defaults.clone();
+    let null_missing_fields = state.config.null_missing_fields.unwrap_or(false);
+    let resolve = lua
+        .create_function(move |lua, reference: String| {
+            match resolve_reference_string(
+                &reference,
+                &objects_arc,
+                &config_defaults,
+                null_missing_fields,
+            ) {
+                Some(value) => json_to_lua(lua, &value),
+                None => Ok(LuaValue::Nil),
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    lua.globals()
+        .set("resolve", resolve)
+        .map_err(|e| e.to_string())?;
+
+    // Snapshot the store and release the lock so `resolve` can take it during the script
+    let mut lua_objects: HashMap<String, Vec<Value>> = HashMap::new();
+    for (object_type, stored_objects) in state.objects.read().unwrap().iter() {
         let data_objects: Vec<Value> = stored_objects.iter().map(|obj| obj.data.clone()).collect();
         lua_objects.insert(object_type.clone(), data_objects);
     }
 
-    let objects_value = lua.to_value(&lua_objects).map_err(|e| e.to_string())?;
+    let objects_value = json_to_lua(&lua, &json!(lua_objects)).map_err(|e| e.to_string())?;
     lua.globals()
         .set("objects", objects_value)
         .map_err(|e| e.to_string())?;
@@ -76,12 +164,18 @@ pub async fn execute_lua_script(
         .map_err(|e| e.to_string())?;
 
     if let Some(body) = &request_context.body {
-        let body_value = lua.to_value(body).map_err(|e| e.to_string())?;
+        let body_value = json_to_lua(&lua, body).map_err(|e| e.to_string())?;
         request_table
             .set("body", body_value)
             .map_err(|e| e.to_string())?;
     }
 
+    if let Some(raw_body) = &request_context.raw_body {
+        request_table
+            .set("raw_body", raw_body.clone())
+            .map_err(|e| e.to_string())?;
+    }
+
     let path_params_table = lua.create_table().map_err(|e| e.to_string())?;
     for (key, value) in &request_context.path_params {
         path_params_table
@@ -92,6 +186,12 @@ pub async fn execute_lua_script(
         .set("path_params", path_params_table)
         .map_err(|e| e.to_string())?;
 
+    let query_value =
+        json_to_lua(&lua, &json!(request_context.query)).map_err(|e| e.to_string())?;
+    request_table
+        .set("query", query_value)
+        .map_err(|e| e.to_string())?;
+
     lua.globals()
         .set("request", request_table)
         .map_err(|e| e.to_string())?;
@@ -104,3 +204,31 @@ pub async fn execute_lua_script(
 
     Ok(json_result)
 }
+
+/// Converts JSON to Lua by hand because serde_json's `arbitrary_precision` makes numbers
+/// serialize as a private map, which `to_value` would hand to scripts as a table.
+/// Numbers too large for a Lua integer become floats.
+fn json_to_lua<'lua>(lua: &'lua Lua, value: &Value) -> mlua::Result<LuaValue<'lua>> {
+    match value {
+        Value::Number(number) => Ok(match number.as_i64() {
+            Some(integer) => LuaValue::Integer(integer),
+            None => LuaValue::Number(number.as_f64().unwrap_or(f64::NAN)),
+        }),
+        Value::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(json_to_lua(lua, item)?)?;
+            }
+            table.set_metatable(Some(lua.array_metatable()));
+            Ok(LuaValue::Table(table))
+        }
+        Value::Object(map) => {
+            let table = lua.create_table_with_capacity(0, map.len())?;
+            for (key, item) in map {
+                table.raw_set(key.as_str(), json_to_lua(lua, item)?)?;
+            }
+            Ok(LuaValue::Table(table))
+        }
+        Value::Null | Value::Bool(_) | Value::String(_) => lua.to_value(value),
+    }
+}
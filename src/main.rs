@@ -1,26 +1,34 @@
-use axum::{
-    Router,
-    extract::{Request, State},
-    http::{Method, StatusCode},
-    response::{IntoResponse, Json},
-    routing::{any, get, post},
+use axum::Router;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::serve::Listener;
+use clap::{Parser, ValueEnum};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder as ConnectionBuilder;
+use hyper_util::service::TowerToHyperService;
+use nugget::listener::ConnectionLimit;
+use nugget::{
+    AppState, ConfigFormat, ServerOptions, build_router, load_config, load_config_as,
+    load_config_dir, load_defaults_file,
 };
-use clap::Parser;
-use serde_json::{Value, json};
-use std::collections::HashMap;
-use std::fs;
-use std::sync::{Arc, RwLock};
+use std::io::IsTerminal;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tower::ServiceExt;
+use tracing::{Level, info};
 
-mod cross_references;
-mod interpolation;
-mod lua_engine;
-mod request_processing;
-mod types;
-mod variable_generation;
-
-use request_processing::{find_matching_route, process_response};
-use types::{AppState, Config};
+/// How the server announces itself once it is listening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Banner {
+    /// A human-readable log line
+    Text,
+    /// One JSON line on stdout, for log ingestion
+    Json,
+    Off,
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "nugget")]
@@ -29,131 +37,337 @@ struct Args {
     #[arg(short, long, default_value = "config.yaml")]
     config: String,
 
+    /// Parse --config as yaml, json or json5 instead of going by its extension
+    #[arg(long)]
+    config_format: Option<ConfigFormat>,
+
+    /// Load and merge every .yaml/.json/.json5 file in this directory instead of --config
+    #[arg(long)]
+    config_dir: Option<String>,
+
     #[arg(short, long, default_value = "3000")]
     port: u16,
+
+    /// Enable POST /admin/routes for registering routes at runtime
+    #[arg(long)]
+    admin_routes: bool,
+
+    /// Record matched requests and expose them via GET /_history
+    #[arg(long)]
+    history: bool,
+
+    /// Number of requests kept per route in the history
+    #[arg(long, default_value = "100")]
+    history_size: usize,
+
+    /// Count requests per route and expose the totals via GET /_stats
+    #[arg(long)]
+    stats: bool,
+
+    /// Resolve {env.NAME} in responses from the environment; off so secrets can't leak
+    #[arg(long)]
+    allow_env: bool,
+
+    /// Attach variable generation warnings to responses under `_warnings`
+    #[arg(long)]
+    debug: bool,
+
+    /// Pretty-print JSON response bodies
+    #[arg(long)]
+    pretty: bool,
+
+    /// Serve an OpenAPI spec at /openapi.json and Swagger UI at /docs
+    #[arg(long)]
+    open_api_ui: bool,
+
+    /// Deepest template/payload nesting allowed before responding with 500 (default: 128)
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// List the routes' Lua scripts via GET /_lua
+    #[arg(long)]
+    inspect_lua: bool,
+
+    /// Omit script source from GET /_lua
+    #[arg(long, requires = "inspect_lua")]
+    no_source: bool,
+
+    /// Return cross-referenced object lists in a random order on every request
+    #[arg(long)]
+    shuffle_objects: bool,
+
+    /// Seed for --shuffle-objects so the sequence of orders is reproducible
+    #[arg(long, requires = "shuffle_objects")]
+    seed: Option<u64>,
+
+    /// Maximum number of open connections; more wait until one closes
+    #[arg(long)]
+    max_connections: Option<NonZeroUsize>,
+
+    /// Number of runtime worker threads handling requests (default: number of CPUs)
+    #[arg(long)]
+    worker_threads: Option<NonZeroUsize>,
+
+    /// Also accept HTTP/2 over cleartext (h2c with prior knowledge)
+    #[arg(long)]
+    http2: bool,
+
+    /// Listen on this Unix domain socket instead of a TCP port
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
+
+    /// Only log errors (equivalent to --log-level error)
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Startup announcement: text, json (one line on stdout) or off
+    #[arg(long, value_enum, default_value = "text")]
+    banner: Banner,
+
+    /// Log verbosity: error, warn, info, debug or trace
+    #[arg(long, default_value = "info")]
+    log_level: Level,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+impl Args {
+    fn worker_threads(&self) -> usize {
+        self.worker_threads
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(args.worker_threads())
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
 
-    let config_content = fs::read_to_string(&args.config)?;
-    let config: Config = if args.config.ends_with(".yaml") || args.config.ends_with(".yml") {
-        serde_yaml::from_str(&config_content)?
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let log_level = if args.quiet {
+        Level::ERROR
     } else {
-        serde_json::from_str(&config_content)?
+        args.log_level
     };
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
 
-    let state = AppState {
-        config: config.clone(),
-        storage: Arc::new(RwLock::new(HashMap::new())),
-        objects: Arc::new(RwLock::new(HashMap::new())),
-        lua_state: Arc::new(RwLock::new(HashMap::new())),
+    let loaded = match (&args.config_dir, args.config_format) {
+        (Some(dir), _) => load_config_dir(dir),
+        (None, Some(format)) => load_config_as(&args.config, format),
+        (None, None) => load_config(&args.config),
+    };
+    // `defaults_file` is relative to the config, not to where the server was started
+    let base_dir = match &args.config_dir {
+        Some(dir) => Path::new(dir),
+        None => Path::new(&args.config).parent().unwrap_or(Path::new("")),
+    };
+    let loaded = loaded.and_then(|config| load_defaults_file(config, base_dir));
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     };
 
-    let mut app = Router::new();
-
-    for route in &config.routes {
-        let path = &route.path;
-        let method = route.method.to_uppercase();
+    let options = ServerOptions {
+        admin_routes: args.admin_routes,
+        history: args.history,
+        history_size: args.history_size,
+        stats: args.stats,
+        allow_env: args.allow_env,
+        debug: args.debug,
+        pretty: args.pretty,
+        open_api_ui: args.open_api_ui,
+        max_depth: args.max_depth,
+        shuffle_objects: args.shuffle_objects,
+        seed: args.seed,
+        inspect_lua: args.inspect_lua,
+        hide_lua_source: args.no_source,
+    };
+    let state = AppState::with_options(config, options);
+    let app = build_router(state.clone());
 
-        match method.as_str() {
-            "GET" => {
-                app = app.route(path, get(handle_request));
-            }
-            "POST" => {
-                app = app.route(path, post(handle_request));
-            }
-            _ => {
-                app = app.route(path, any(handle_request));
-            }
-        }
+    if let Some(socket_path) = &args.unix_socket {
+        return serve_unix(socket_path, app, &state, &args).await;
     }
 
-    app = app.route("/state/clear", post(clear_state));
-
     let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
-    println!("Server running on http://0.0.0.0:{}", args.port);
+    let port = listener.local_addr()?.port();
+    state.mark_ready();
+    announce(&args, &state, &format!("http://0.0.0.0:{port}"), Some(port));
 
-    axum::serve(listener, app.with_state(state)).await?;
+    serve(listener, app, args.max_connections, args.http2).await?;
     Ok(())
 }
 
-async fn clear_state(State(state): State<AppState>) -> Json<Value> {
-    {
-        let mut objects = state.objects.write().unwrap();
-        objects.clear();
-    }
-    {
-        let mut storage = state.storage.write().unwrap();
-        storage.clear();
+/// Reports that the server is listening on `address`, in the format `--banner` asks for.
+fn announce(args: &Args, state: &AppState, address: &str, port: Option<u16>) {
+    match args.banner {
+        Banner::Text => info!("Server running on {address}"),
+        Banner::Json => {
+            let config = args.config_dir.as_ref().unwrap_or(&args.config);
+            let startup = serde_json::json!({
+                "event": "startup",
+                "address": address,
+                "port": port,
+                "routes": state.config.routes.len(),
+                "config": config,
+                "version": env!("CARGO_PKG_VERSION"),
+                "worker_threads": args.worker_threads(),
+            });
+            println!("{startup}");
+        }
+        Banner::Off => {}
     }
-    {
-        let mut lua_state = state.lua_state.write().unwrap();
-        lua_state.clear();
+}
+
+#[cfg(unix)]
+async fn serve_unix(
+    socket_path: &Path,
+    app: Router,
+    state: &AppState,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left by a killed run would make bind fail, but anything else at that path is
+    // not ours to delete
+    match std::fs::symlink_metadata(socket_path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(socket_path)?,
+        Ok(_) => {
+            return Err(format!(
+                "{} already exists and is not a socket",
+                socket_path.display()
+            )
+            .into());
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
     }
 
-    Json(json!({
-        "status": "cleared",
-        "message": "All stored state has been cleared"
-    }))
+    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    state.mark_ready();
+    announce(
+        args,
+        state,
+        &format!("unix:{}", socket_path.display()),
+        None,
+    );
+
+    let result = serve(listener, app, args.max_connections, args.http2).await;
+    let _ = std::fs::remove_file(socket_path);
+    Ok(result?)
 }
 
-async fn handle_request(
-    State(state): State<AppState>,
-    req: Request,
-) -> Result<impl IntoResponse, StatusCode> {
-    let method = req.method().clone();
-    let path = req.uri().path().to_string();
-
-    let headers: HashMap<String, String> = req
-        .headers()
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-        .collect();
-
-    let payload = if method == Method::POST || method == Method::PUT || method == Method::PATCH {
-        let body = axum::body::to_bytes(req.into_body(), usize::MAX)
-            .await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-        if !body.is_empty() {
-            Some(serde_json::from_slice::<Value>(&body).map_err(|_| StatusCode::BAD_REQUEST)?)
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+#[cfg(not(unix))]
+async fn serve_unix(
+    _socket_path: &Path,
+    _app: Router,
+    _state: &AppState,
+    _args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("--unix-socket is only supported on Unix platforms".into())
+}
+
+/// Serves `app` until Ctrl-C or SIGTERM, letting in-flight requests finish.
+async fn serve<L>(
+    listener: L,
+    app: Router,
+    max_connections: Option<NonZeroUsize>,
+    http2: bool,
+) -> std::io::Result<()>
+where
+    L: Listener,
+    L::Addr: Clone + Send + Sync + 'static,
+{
+    match max_connections {
+        Some(max) => serve_connections(ConnectionLimit::new(listener, max.get()), app, http2).await,
+        None => serve_connections(listener, app, http2).await,
+    }
+}
 
-    let route = find_matching_route(&state.config, method.as_ref(), &path);
+/// Accepts connections until Ctrl-C or SIGTERM, then lets in-flight requests finish.
+/// Connections speak HTTP/1.1 only unless `http2` is set, which also accepts HTTP/2
+/// with prior knowledge. axum::serve can't be used here: it always auto-detects h2
+/// once hyper's `http2` feature is enabled anywhere in the dependency graph.
+async fn serve_connections<L>(mut listener: L, app: Router, http2: bool) -> std::io::Result<()>
+where
+    L: Listener,
+    L::Addr: Clone + Send + Sync + 'static,
+{
+    let mut builder = ConnectionBuilder::new(TokioExecutor::new());
+    if !http2 {
+        builder = builder.http1_only();
+    }
 
-    if let Some(route) = route {
-        let response = process_response(&state, &route, &path, payload.as_ref(), &headers).await;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
-        // Check for Lua script status (top-level status field)
-        if let Some(status_value) = response.get("status") {
-            if let Some(status_code) = status_value.as_u64() {
-                let status = StatusCode::from_u16(status_code as u16)
-                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    loop {
+        let (io, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
 
-                let body = response.get("body").unwrap_or(&response).clone();
+        // Stands in for axum's connect info, so handlers can see the client's address
+        let service =
+            TowerToHyperService::new(app.clone().map_request(move |mut req: Request<Incoming>| {
+                req.extensions_mut()
+                    .insert(ConnectInfo(remote_addr.clone()));
+                req
+            }));
+        let mut shutdown_rx = shutdown_rx.clone();
+        let builder = builder.clone();
+        connections.spawn(async move {
+            let connection = builder.serve_connection(TokioIo::new(io), service);
+            tokio::pin!(connection);
 
-                return Ok((status, Json(body)).into_response());
+            tokio::select! {
+                _ = connection.as_mut() => {}
+                _ = shutdown_rx.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    let _ = connection.await;
+                }
             }
-        }
+        });
+    }
 
-        // Check for traditional template status
-        if let Some(response_template) = &route.response {
-            if let Some(template_status) = response_template.status {
-                let status = StatusCode::from_u16(template_status)
-                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    drop(shutdown_tx);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
 
-                return Ok((status, Json(response)).into_response());
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
+            Err(_) => std::future::pending::<()>().await,
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        Ok(Json(response).into_response())
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+    info!("Shutting down");
 }
@@ -0,0 +1,50 @@
+use axum::body::Bytes;
+use serde_json::{Map, Value, json};
+use std::convert::Infallible;
+
+/// Returns the boundary when `content_type` is `multipart/form-data`.
+pub fn multipart_boundary(content_type: Option<&str>) -> Option<String> {
+    let content_type = content_type?;
+    if !content_type
+        .trim_start()
+        .to_ascii_lowercase()
+        .starts_with("multipart/form-data")
+    {
+        return None;
+    }
+    multer::parse_boundary(content_type).ok()
+}
+
+/// Parses a multipart body into an object keyed by part name. Text fields become
+/// strings and file parts become `{filename, content_type, size}`; a name sent
+/// more than once collects its values into an array.
+pub async fn parse_multipart(body: Bytes, boundary: String) -> Result<Value, multer::Error> {
+    let stream = futures_util::stream::once(async move { Ok::<_, Infallible>(body) });
+    let mut multipart = multer::Multipart::new(stream, boundary);
+    let mut fields = Map::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let Some(name) = field.name().map(str::to_string) else {
+            continue;
+        };
+
+        let value = match field.file_name().map(str::to_string) {
+            Some(filename) => {
+                let content_type = field.content_type().map(|mime| mime.to_string());
+                let size = field.bytes().await?.len();
+                json!({"filename": filename, "content_type": content_type, "size": size})
+            }
+            None => json!(field.text().await?),
+        };
+
+        match fields.get_mut(&name) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = json!([existing.take(), value]),
+            None => {
+                fields.insert(name, value);
+            }
+        }
+    }
+
+    Ok(Value::Object(fields))
+}
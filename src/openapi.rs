@@ -0,0 +1,125 @@
+use crate::interpolation::is_optional_segment;
+use crate::route_table::RouteTable;
+use crate::types::Route;
+use serde_json::{Map, Value, json};
+
+/// Swagger UI page served at `/docs`, loading the assets from a CDN and the spec
+/// from `/openapi.json`.
+pub const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>Nugget API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+/// Builds an OpenAPI 3 document describing every route in the table, using each
+/// response template's body as the example.
+pub fn generate_spec(routes: &RouteTable) -> Value {
+    let mut paths = Map::new();
+
+    for route in routes.routes() {
+        for path in spec_paths(&route.path) {
+            let operation = operation(route, &path);
+            let operations = paths.entry(path).or_insert_with(|| json!({}));
+            if let Some(operations) = operations.as_object_mut() {
+                operations.insert(route.method.to_lowercase(), operation);
+            }
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Nugget mock API",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths
+    })
+}
+
+/// The spec's paths for a route. OpenAPI has no optional segments, so a route ending in
+/// `{name?}` is listed both with a required `{name}` and without the segment.
+fn spec_paths(path: &str) -> Vec<String> {
+    match path.rsplit_once('/') {
+        Some((parent, last)) if is_optional_segment(last) => {
+            let with_segment = format!("{parent}/{}", last.replace("?}", "}"));
+            let without_segment = if parent.is_empty() { "/" } else { parent };
+            vec![with_segment, without_segment.to_string()]
+        }
+        _ => vec![path.to_string()],
+    }
+}
+
+fn operation(route: &Route, path: &str) -> Value {
+    let method = route.method.to_uppercase();
+    let mut operation = json!({
+        "summary": format!("{} {}", method, path),
+        "responses": responses(route)
+    });
+
+    let parameters: Vec<Value> = path
+        .split(['{', '}'])
+        .skip(1)
+        .step_by(2)
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": {"type": "string"}
+            })
+        })
+        .collect();
+    if !parameters.is_empty() {
+        operation["parameters"] = json!(parameters);
+    }
+
+    if matches!(method.as_str(), "POST" | "PUT" | "PATCH") {
+        operation["requestBody"] = json!({
+            "required": false,
+            "content": {"application/json": {"schema": {"type": "object"}}}
+        });
+    }
+
+    operation
+}
+
+fn responses(route: &Route) -> Value {
+    let Some(template) = &route.response else {
+        let description = if route.lua_script.is_some() {
+            "Generated by a Lua script"
+        } else {
+            "No response template configured"
+        };
+        return json!({"default": {"description": description}});
+    };
+
+    let mut content = Map::new();
+    content.insert(
+        "application/json".to_string(),
+        json!({"example": template.body}),
+    );
+    for (media_type, alternative) in route.responses_by_accept.iter().flatten() {
+        content.insert(media_type.clone(), json!({"example": alternative.body}));
+    }
+
+    let status = template.status.unwrap_or(200).to_string();
+    json!({
+        status: {
+            "description": "Templated response",
+            "content": content
+        }
+    })
+}
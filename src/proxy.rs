@@ -0,0 +1,76 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, Method, StatusCode, Uri, header},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Hop-by-hop headers that describe a single connection and must not be forwarded.
+const HOP_BY_HOP_HEADERS: [header::HeaderName; 5] = [
+    header::CONNECTION,
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Forwards a request to `upstream` and relays its response, or returns 502 if it can't be reached.
+pub async fn proxy_request(
+    upstream: &str,
+    method: Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path_and_query = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let url = format!("{}{}", upstream.trim_end_matches('/'), path_and_query);
+
+    let mut forwarded_headers = headers.clone();
+    for name in &HOP_BY_HOP_HEADERS {
+        forwarded_headers.remove(name);
+    }
+
+    let upstream_response = match client()
+        .request(method, &url)
+        .headers(forwarded_headers)
+        .body(body)
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Proxy request to {url} failed: {e}");
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({"error": "Upstream request failed", "upstream": url})),
+            )
+                .into_response();
+        }
+    };
+
+    let status = upstream_response.status();
+    let mut response_headers = upstream_response.headers().clone();
+    for name in &HOP_BY_HOP_HEADERS {
+        response_headers.remove(name);
+    }
+
+    let body = match upstream_response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read proxied response from {url}: {e}");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = response_headers;
+    response
+}
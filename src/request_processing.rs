@@ -1,124 +1,490 @@
-use crate::cross_references::resolve_cross_references;
-use crate::interpolation::{extract_path_parameters, interpolate_payload, replace_path_parameters};
+use crate::cross_references::{expand_for_each, resolve_cross_references};
+use crate::interpolation::{
+    extract_path_parameters, interpolate_payload, replace_context_values, replace_env_values,
+    replace_path_parameters, replace_query_parameters, resolve_conditionals,
+};
 use crate::lua_engine::execute_lua_script;
-use crate::types::{AppState, Config, LuaRequestContext, Route, StoredObject};
-use crate::variable_generation::{generate_variable_value, replace_variables_in_value};
+use crate::route_table::RouteTable;
+use crate::types::{
+    AppState, LuaRequestContext, PaginationConfig, ResponseTemplate, Route, StatusStep,
+    StoredObject,
+};
+use crate::variable_generation::{
+    derive_variable_value, generate_sequence, generate_sequence_time, generate_variable_value,
+    is_variable_present, omit_variables_in_value, replace_variables_in_value, variable_order,
+};
 use serde_json::{Value, json};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
 
-pub fn find_matching_route(config: &Config, method: &str, path: &str) -> Option<Route> {
-    for route in &config.routes {
-        if route.method.to_uppercase() == method.to_uppercase()
-            && (route.path == path || path_matches_pattern(&route.path, path))
-        {
-            return Some(route.clone());
+pub fn find_matching_route(routes: &RouteTable, method: &str, path: &str) -> Option<Arc<Route>> {
+    routes.find(method, path)
+}
+
+/// Picks the `param_cases` template for a path parameter value, else the template
+/// for the most preferred Accept media type with a configured alternative, falling
+/// back to the route's default `response`.
+pub fn select_response_template<'a>(
+    route: &'a Route,
+    path: &str,
+    headers: &HashMap<String, String>,
+) -> Option<&'a ResponseTemplate> {
+    if let Some(cases) = &route.param_cases {
+        let mut params: Vec<_> = extract_path_parameters(&route.path, path)
+            .into_iter()
+            .collect();
+        // Parameters are checked in name order so overlapping cases pick consistently
+        params.sort();
+        if let Some(template) = params.iter().find_map(|(_, value)| cases.get(value)) {
+            return Some(template);
         }
     }
-    None
+
+    if let (Some(by_accept), Some(accept)) = (&route.responses_by_accept, headers.get("accept")) {
+        let mut media_types: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|entry| {
+                let mut params = entry.split(';');
+                let media_type = params.next().unwrap_or("").trim();
+                let quality = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .collect();
+        media_types.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (media_type, _) in media_types {
+            if let Some((_, template)) = by_accept
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(media_type))
+            {
+                return Some(template);
+            }
+        }
+    }
+
+    route.response.as_ref()
 }
 
-fn path_matches_pattern(pattern: &str, path: &str) -> bool {
-    let pattern_parts: Vec<&str> = pattern.split('/').collect();
-    let path_parts: Vec<&str> = path.split('/').collect();
+pub async fn process_response(
+    state: &AppState,
+    route: &Route,
+    path: &str,
+    payload: Option<&Value>,
+    raw_body: Option<&str>,
+    headers: &HashMap<String, String>,
+    query: &HashMap<String, Value>,
+) -> Value {
+    if let Some(error) = validate_field_patterns(route, payload) {
+        return error;
+    }
 
-    if pattern_parts.len() != path_parts.len() {
-        return false;
+    if let Some(degraded) = &route.degraded
+        && state.request_count.load(Ordering::SeqCst) > degraded.after_requests
+    {
+        return json!({
+            "status": degraded.response.status.unwrap_or(200),
+            "body": degraded.response.body
+        });
     }
 
-    for (pattern_part, path_part) in pattern_parts.iter().zip(path_parts.iter()) {
-        if pattern_part.starts_with('{') && pattern_part.ends_with('}') {
+    let (status, body) = if let Some(step) = next_status_step(state, route) {
+        (step.status, step.body.as_ref())
+    } else if let Some(status) = pick_weighted_status(route) {
+        let bodies = route.status_bodies.as_ref();
+        (status, bodies.and_then(|bodies| bodies.get(&status)))
+    } else {
+        return generate_response(state, route, path, payload, raw_body, headers, query).await;
+    };
+
+    let body = match body {
+        Some(body) => body.clone(),
+        None => generate_response(state, route, path, payload, raw_body, headers, query).await,
+    };
+    json!({"status": status, "body": body})
+}
+
+/// Checks each payload field named in `field_patterns` against its regex, which must
+/// match the whole value. Returns the error response for the first field that fails.
+fn validate_field_patterns(route: &Route, payload: Option<&Value>) -> Option<Value> {
+    let patterns = route.field_patterns.as_ref()?;
+    let regexes = match route.field_regexes() {
+        Ok(regexes) => regexes,
+        Err(e) => return Some(json!({"error": format!("Route has an {e}"), "status": 500})),
+    };
+
+    for (field, regex) in regexes {
+        let pattern = &patterns[field];
+
+        let Some(value) = payload.and_then(|p| p.get(field)) else {
             continue;
+        };
+        let text = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        if !regex.is_match(&text) {
+            return Some(json!({
+                "status": 422,
+                "body": {
+                    "error": format!("Field '{field}' does not match the required pattern"),
+                    "field": field,
+                    "pattern": pattern
+                }
+            }));
+        }
+    }
+
+    None
+}
+
+/// Draws a status from `status_weights`, each with probability proportional to its weight.
+fn pick_weighted_status(route: &Route) -> Option<u16> {
+    let weights = route.status_weights.as_ref()?;
+    let mut statuses: Vec<(&u16, &f64)> = weights.iter().filter(|(_, w)| **w > 0.0).collect();
+    // Sorted so a given draw always maps to the same status
+    statuses.sort_by_key(|(status, _)| **status);
+
+    let total: f64 = statuses.iter().map(|(_, weight)| **weight).sum();
+    let mut draw = rand::random::<f64>() * total;
+    for (status, weight) in &statuses {
+        if draw < **weight {
+            return Some(**status);
         }
-        if pattern_part != path_part {
-            return false;
+        draw -= **weight;
+    }
+    // Rounding can leave the draw just past the last weight
+    statuses.last().map(|(status, _)| **status)
+}
+
+/// Checks an object about to be stored against the route's `object_schema`. Returns the
+/// 422 response listing every violation, so a bad fixture never reaches the store.
+fn check_object_schema(route: &Route, object: &Value) -> Option<Value> {
+    let validator = match route.schema_validator()? {
+        Ok(validator) => validator,
+        Err(e) => {
+            return Some(json!({
+                "error": format!("Invalid object_schema: {e}"),
+                "status": 500
+            }));
         }
+    };
+
+    let errors: Vec<Value> = validator
+        .iter_errors(object)
+        .map(|error| json!({"path": error.instance_path.to_string(), "message": error.to_string()}))
+        .collect();
+    if errors.is_empty() {
+        return None;
     }
+    Some(json!({
+        "status": 422,
+        "body": {
+            "error": "Object does not match the route's object_schema",
+            "errors": errors
+        }
+    }))
+}
 
-    true
+/// Checks a request payload against the route's `max_payload_depth` and
+/// `max_payload_fields`, returning why it was rejected.
+pub fn check_payload_limits(route: &Route, payload: &Value) -> Option<String> {
+    if let Some(max) = route.max_payload_depth
+        && exceeds_depth(payload, max)
+    {
+        return Some(format!(
+            "Request payload nesting exceeds the route's max_payload_depth of {max}"
+        ));
+    }
+    if let Some(max) = route.max_payload_fields
+        && count_fields(payload, max) > max
+    {
+        return Some(format!(
+            "Request payload has more than the route's max_payload_fields of {max} fields"
+        ));
+    }
+    None
 }
 
-pub async fn process_response(
+/// Counts object fields at every level of `value`, giving up once the count passes `max`.
+fn count_fields(value: &Value, max: usize) -> usize {
+    let mut count = 0;
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match value {
+            Value::Array(items) => pending.extend(items),
+            Value::Object(map) => {
+                count += map.len();
+                if count > max {
+                    break;
+                }
+                pending.extend(map.values());
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Advances the route's request counter and returns the `pattern_status` step it lands on.
+fn next_status_step<'a>(state: &AppState, route: &'a Route) -> Option<&'a StatusStep> {
+    let steps = route.pattern_status.as_ref()?;
+    let cycle_length: u64 = steps.iter().map(|step| step.count).sum();
+    if cycle_length == 0 {
+        return None;
+    }
+
+    let key = format!("{} {}", route.method.to_uppercase(), route.path);
+    let seen = {
+        let mut counters = state.route_counters.write().unwrap();
+        let counter = counters.entry(key).or_default();
+        *counter += 1;
+        *counter - 1
+    };
+
+    let mut position = seen % cycle_length;
+    steps.iter().find(|step| {
+        if position < step.count {
+            true
+        } else {
+            position -= step.count;
+            false
+        }
+    })
+}
+
+async fn generate_response(
     state: &AppState,
     route: &Route,
     path: &str,
     payload: Option<&Value>,
+    raw_body: Option<&str>,
     headers: &HashMap<String, String>,
+    query: &HashMap<String, Value>,
 ) -> Value {
     let path_params = extract_path_parameters(&route.path, path);
 
+    if is_json_patch_request(route, headers) {
+        return apply_json_patch(state, route, path, &path_params, payload);
+    }
+    let method = route.method.to_uppercase();
+
+    if let Some(pagination) = &route.paginate {
+        return paginate_objects(state, pagination, query);
+    }
+
     if let Some(lua_script) = &route.lua_script {
         let request_context = LuaRequestContext {
             method: route.method.clone(),
             path: path.to_string(),
             headers: headers.clone(),
             body: payload.cloned(),
+            raw_body: raw_body.map(str::to_string),
             path_params: path_params.clone(),
+            query: query.clone(),
         };
 
         match execute_lua_script(lua_script, state, &request_context).await {
+            Ok(result) if route.resolve_refs_in_lua_output.unwrap_or(false) => {
+                let nested = state.config.resolve_stored_references.unwrap_or(false);
+                return resolve_object_references(state, &result, nested).unwrap_or_else(|e| e);
+            }
             Ok(result) => return result,
             Err(_) => return json!({"error": "Failed to execute Lua script", "status": 500}),
         }
     }
 
-    if let Some(response_template) = &route.response {
+    // Routes addressing a stored object by `{id}` update the store per their verb
+    let stored_target = route.object_name.as_ref().zip(path_params.get("id"));
+    if let Some((object_name, id)) = stored_target {
+        match method.as_str() {
+            "PATCH" => match merge_object(state, path, object_name, id, payload) {
+                Ok(merged) if route.response.is_none() => return merged,
+                Ok(_) => {}
+                Err(error) => return error,
+            },
+            "DELETE" => match delete_object(state, path, object_name, id) {
+                Some(deleted) if route.response.is_none() => return deleted,
+                Some(_) => {}
+                None => return json!({"error": "Object not found", "id": id, "status": 404}),
+            },
+            "PUT" if route.response.is_none() => {
+                let Some(payload) = payload else {
+                    return json!({"error": "Missing request body", "status": 422});
+                };
+                put_object(state, path, object_name, id, payload.clone());
+                return payload.clone();
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(response_template) = select_response_template(route, path, headers) {
+        let max_depth = max_depth(state);
+        if exceeds_depth(&response_template.body, max_depth)
+            || payload.is_some_and(|payload| exceeds_depth(payload, max_depth))
+        {
+            return depth_error(max_depth);
+        }
+
         let mut response_body = response_template.body.clone();
+        let mut warnings = Vec::new();
 
         response_body = replace_path_parameters(&response_body, &path_params);
+        response_body = replace_query_parameters(&response_body, query);
+        if let Some(context) = &state.config.context {
+            response_body = replace_context_values(&response_body, context);
+        }
+        if state.options.allow_env {
+            response_body = replace_env_values(&response_body);
+        }
 
-        response_body = resolve_cross_references(&response_body, &state.objects);
-        if route.method.to_uppercase() == "POST" {
-            if let Some(variables) = &route.variables {
-                let mut generated_vars = HashMap::new();
+        // With late references, stored copies keep their `{objects...}` strings so
+        // they're resolved against the store as it is when the object is read
+        let late_references = state.config.resolve_stored_references.unwrap_or(false);
+        let stored_body = late_references.then(|| response_body.clone());
 
-                for (var_name, var_config) in variables {
-                    let value = generate_variable_value(var_config);
-                    generated_vars.insert(var_name.clone(), value);
+        response_body = match resolve_object_references(state, &response_body, late_references) {
+            Ok(resolved) => resolved,
+            Err(error) => return error,
+        };
+        if method == "POST"
+            && (route.variables.is_some() || route.id_strategy.is_some() || route.stores_objects())
+        {
+            let mut draws = SequenceDraws::lock(state);
+            let no_variables = HashMap::new();
+            let variables = route.variables.as_ref().unwrap_or(&no_variables);
+            let order = match variable_order(variables) {
+                Ok(order) => order,
+                Err(e) => return json!({"error": e, "status": 500}),
+            };
+            let mut generated_vars = HashMap::new();
+            let mut omitted_vars = HashSet::new();
+
+            for var_name in order {
+                let var_config = &variables[var_name];
+                // A derived variable disappears along with its source
+                let source = var_config
+                    .from
+                    .as_ref()
+                    .map(|from| generated_vars.get(from));
+                if !is_variable_present(var_config) || source == Some(None) {
+                    omitted_vars.insert(var_name.to_string());
+                    continue;
                 }
+                let value = match source {
+                    Some(Some(source)) => derive_variable_value(var_config, source),
+                    _ if var_config.var_type == "sequence" => {
+                        let position = draws.variable_position(route, var_name);
+                        generate_sequence(var_config, position, &mut warnings)
+                    }
+                    _ if var_config.var_type == "sequence_time" => {
+                        let position = draws.variable_position(route, var_name);
+                        generate_sequence_time(var_config, position, &mut warnings)
+                    }
+                    _ => generate_variable_value(var_config, payload, &mut warnings),
+                };
+                generated_vars.insert(var_name.to_string(), value);
+            }
 
-                response_body = replace_variables_in_value(&response_body, &generated_vars);
+            let object_id = match &route.id_strategy {
+                Some(strategy) => match strategy_id(&mut draws, route, strategy, payload) {
+                    Ok(id) => {
+                        // Routes without an `id` variable can still echo the id as `{id}`
+                        if !variables.contains_key("id") {
+                            generated_vars.insert("id".to_string(), id.clone());
+                        }
+                        Some(id)
+                    }
+                    Err(error) => return error,
+                },
+                None => match generated_vars.get("id") {
+                    Some(id) => Some(id.clone()),
+                    // Warned about at startup; a random id still lets the object be stored
+                    None if route.stores_objects() => {
+                        let id = json!(uuid::Uuid::new_v4().to_string());
+                        generated_vars.insert("id".to_string(), id.clone());
+                        Some(id)
+                    }
+                    None => None,
+                },
+            };
 
+            let fill_generated = |body: &Value| {
+                let mut body = omit_variables_in_value(body, &omitted_vars);
+                body = replace_variables_in_value(&body, &generated_vars);
                 if let Some(payload) = payload {
-                    response_body =
-                        interpolate_payload(&response_body, payload, &state.config.defaults);
+                    body = interpolate_payload(&body, payload, &state.config.defaults);
                 }
+                resolve_conditionals(&body, payload, &state.config.defaults)
+            };
+            response_body = fill_generated(&response_body);
+            let mut stored_body = match &stored_body {
+                Some(unresolved) => fill_generated(unresolved),
+                None => response_body.clone(),
+            };
+
+            if object_id.is_some()
+                && let Some(error) = check_object_schema(route, &stored_body)
+            {
+                return error;
+            }
+            draws.commit();
+
+            if let Some(id_value) = &object_id {
+                state.sequence_object(&mut stored_body);
+                if let Some(seq) = stored_body.get("_seq")
+                    && let Some(body) = response_body.as_object_mut()
+                {
+                    body.insert("_seq".to_string(), seq.clone());
+                }
+
+                let id = match id_value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                let storage_key = format!("{}_{}", route.path, id);
+                state
+                    .storage
+                    .write()
+                    .unwrap()
+                    .insert(storage_key, stored_body.clone());
+
+                if let Some(object_name) = &route.object_name
+                    && route.store_object.unwrap_or(true)
+                {
+                    let stored_object = StoredObject {
+                        id,
+                        data: stored_body,
+                    };
 
-                if let Some(id_value) = generated_vars.get("id") {
-                    let storage_key = format!("{}_{}", route.path, id_value);
                     state
-                        .storage
+                        .objects
                         .write()
                         .unwrap()
-                        .insert(storage_key, response_body.clone());
-
-                    if let Some(object_name) = &route.object_name {
-                        if route.store_object.unwrap_or(true) {
-                            let stored_object = StoredObject {
-                                id: id_value.as_str().unwrap_or("").to_string(),
-                                data: response_body.clone(),
-                            };
-
-                            state
-                                .objects
-                                .write()
-                                .unwrap()
-                                .entry(object_name.clone())
-                                .or_default()
-                                .push(stored_object);
-                        }
-                    }
+                        .entry(object_name.clone())
+                        .or_default()
+                        .push(stored_object);
                 }
             }
         }
 
-        if route.method.to_uppercase() == "GET" && path.contains('/') {
+        if method == "GET" && path.contains('/') {
             let path_parts: Vec<&str> = path.split('/').collect();
             if let Some(id) = path_parts.last() {
                 let storage_key =
                     format!("{}_{}", path_parts[..path_parts.len() - 1].join("/"), id);
 
-                if let Some(stored_response) = state.storage.read().unwrap().get(&storage_key) {
-                    return stored_response.clone();
+                let stored_response = state.storage.read().unwrap().get(&storage_key).cloned();
+                if let Some(stored_response) = stored_response {
+                    if late_references {
+                        return resolve_object_references(state, &stored_response, true)
+                            .unwrap_or_else(|e| e);
+                    }
+                    return stored_response;
                 }
             }
         }
@@ -126,9 +492,383 @@ pub async fn process_response(
         if let Some(payload) = payload {
             response_body = interpolate_payload(&response_body, payload, &state.config.defaults);
         }
+        response_body = resolve_conditionals(&response_body, payload, &state.config.defaults);
+
+        if method == "PUT"
+            && let Some((object_name, id)) = stored_target
+        {
+            if let Some(error) = check_object_schema(route, &response_body) {
+                return error;
+            }
+            put_object(state, path, object_name, id, response_body.clone());
+        }
+
+        if state.options.debug
+            && !warnings.is_empty()
+            && let Some(body) = response_body.as_object_mut()
+        {
+            body.insert("_warnings".to_string(), json!(warnings));
+        }
 
         response_body
     } else {
         json!({"error": "No response template defined", "status": 500})
     }
 }
+
+/// Sequence values drawn while a POST builds its object. The counters stay locked meanwhile
+/// and only advance on `commit`, so a request rejected by `object_schema` leaves no gap.
+struct SequenceDraws<'a> {
+    variable_counters: RwLockWriteGuard<'a, HashMap<String, u64>>,
+    id_sequences: RwLockWriteGuard<'a, HashMap<String, u64>>,
+    drawn_variables: Vec<(String, u64)>,
+    drawn_id: Option<(String, u64)>,
+}
+
+impl<'a> SequenceDraws<'a> {
+    fn lock(state: &'a AppState) -> Self {
+        SequenceDraws {
+            variable_counters: state.variable_counters.write().unwrap(),
+            id_sequences: state.id_sequences.write().unwrap(),
+            drawn_variables: Vec::new(),
+            drawn_id: None,
+        }
+    }
+
+    /// Draws a value for a sequence variable, returning how many came before it. Each
+    /// route and variable name counts separately, from 0 after startup or `/state/clear`.
+    fn variable_position(&mut self, route: &Route, var_name: &str) -> u64 {
+        let key = format!("{} {} {var_name}", route.method.to_uppercase(), route.path);
+        let position = self
+            .variable_counters
+            .get(&key)
+            .copied()
+            .unwrap_or_default();
+        self.drawn_variables.push((key, position + 1));
+        position
+    }
+
+    /// Draws the next `sequence` id for an object type, counting from 1.
+    fn next_id(&mut self, object_type: &str) -> u64 {
+        let next = self
+            .id_sequences
+            .get(object_type)
+            .copied()
+            .unwrap_or_default()
+            + 1;
+        self.drawn_id = Some((object_type.to_string(), next));
+        next
+    }
+
+    /// Advances the counters past every value drawn.
+    fn commit(mut self) {
+        for (key, count) in self.drawn_variables.drain(..) {
+            self.variable_counters.insert(key, count);
+        }
+        if let Some((object_type, last)) = self.drawn_id.take() {
+            self.id_sequences.insert(object_type, last);
+        }
+    }
+}
+
+/// Ways `id_strategy` can derive a stored object's id.
+pub const ID_STRATEGIES: [&str; 4] = ["uuid", "sequence", "payload_field", "hash"];
+
+/// Derives the id a POST stores its object under from the route's `id_strategy`.
+/// Sequences count per object type from 1; hashes are stable for equal payloads.
+fn strategy_id(
+    draws: &mut SequenceDraws,
+    route: &Route,
+    strategy: &str,
+    payload: Option<&Value>,
+) -> Result<Value, Value> {
+    match strategy {
+        "sequence" => {
+            let object_type = route.object_name.as_ref().unwrap_or(&route.path);
+            Ok(json!(draws.next_id(object_type)))
+        }
+        "payload_field" => {
+            let field = route.id_field.as_deref().unwrap_or("id");
+            match payload.and_then(|payload| payload.get(field)) {
+                Some(id @ (Value::String(_) | Value::Number(_))) => Ok(id.clone()),
+                _ => Err(json!({
+                    "error": format!("Payload field '{field}' must hold the object's id"),
+                    "field": field,
+                    "status": 422
+                })),
+            }
+        }
+        "hash" => {
+            let bytes = payload.map(Value::to_string).unwrap_or_default();
+            Ok(json!(format!("{:016x}", fnv1a(bytes.as_bytes()))))
+        }
+        _ => Ok(json!(uuid::Uuid::new_v4().to_string())),
+    }
+}
+
+/// 64-bit FNV-1a, used over std's hasher because ids must not change between runs.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Stored objects pulled in by a reference may themselves hold references when
+/// `resolve_stored_references` is on, so resolution repeats up to this depth.
+const MAX_REFERENCE_DEPTH: usize = 5;
+
+/// Nesting allowed in templates, payloads and resolved bodies unless `--max-depth` is given.
+const DEFAULT_MAX_DEPTH: usize = 128;
+
+fn max_depth(state: &AppState) -> usize {
+    state.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+}
+
+/// True when arrays and objects nest more than `max` levels deep. Stops descending at
+/// the limit, so checking a pathological value can't itself exhaust the stack.
+fn exceeds_depth(value: &Value, max: usize) -> bool {
+    match value {
+        Value::Array(items) => max == 0 || items.iter().any(|v| exceeds_depth(v, max - 1)),
+        Value::Object(map) => max == 0 || map.values().any(|v| exceeds_depth(v, max - 1)),
+        _ => false,
+    }
+}
+
+fn depth_error(max_depth: usize) -> Value {
+    json!({
+        "error": format!("Response generation aborted: nesting exceeds the maximum depth of {max_depth}"),
+        "status": 500
+    })
+}
+
+/// Expands `for_each` nodes and `{objects...}` references, repeating while resolved
+/// objects bring in references of their own when `nested` is set. Fails with an error
+/// response when the body nests deeper than the maximum depth.
+fn resolve_object_references(state: &AppState, body: &Value, nested: bool) -> Result<Value, Value> {
+    let max_depth = max_depth(state);
+    if exceeds_depth(body, max_depth) {
+        return Err(depth_error(max_depth));
+    }
+
+    // One shuffled snapshot per response, so lists and their projections stay aligned
+    let shuffled = state
+        .options
+        .shuffle_objects
+        .then(|| Arc::new(RwLock::new(state.shuffled_objects())));
+    let objects = shuffled.as_ref().unwrap_or(&state.objects);
+
+    let resolve = |body: &Value| {
+        let null_missing_fields = state.config.null_missing_fields.unwrap_or(false);
+        let expanded = expand_for_each(body, objects, &state.config.defaults, null_missing_fields);
+        resolve_cross_references(
+            &expanded,
+            objects,
+            &state.config.defaults,
+            null_missing_fields,
+        )
+    };
+
+    let mut resolved = resolve(body);
+    if nested {
+        for _ in 1..MAX_REFERENCE_DEPTH {
+            if exceeds_depth(&resolved, max_depth) {
+                return Err(depth_error(max_depth));
+            }
+            let next = resolve(&resolved);
+            if next == resolved {
+                break;
+            }
+            resolved = next;
+        }
+    }
+    Ok(resolved)
+}
+
+const DEFAULT_PER_PAGE: usize = 10;
+const DEFAULT_MAX_PER_PAGE: usize = 100;
+
+/// Builds one page of a `paginate` route's objects from the `page` and `per_page` query
+/// parameters. Pages past the end are empty rather than errors, as most APIs do.
+fn paginate_objects(
+    state: &AppState,
+    pagination: &PaginationConfig,
+    query: &HashMap<String, Value>,
+) -> Value {
+    let positive = |name: &str| -> Result<Option<usize>, Value> {
+        let Some(value) = query.get(name) else {
+            return Ok(None);
+        };
+        match value.as_str().and_then(|s| s.parse::<usize>().ok()) {
+            Some(n) if n > 0 => Ok(Some(n)),
+            _ => Err(json!({
+                "error": format!("Query parameter '{name}' must be a positive integer"),
+                "status": 400
+            })),
+        }
+    };
+    let (page, per_page) = match (positive("page"), positive("per_page")) {
+        (Ok(page), Ok(per_page)) => (page.unwrap_or(1), per_page),
+        (Err(error), _) | (_, Err(error)) => return error,
+    };
+    let max_per_page = pagination.max_per_page.unwrap_or(DEFAULT_MAX_PER_PAGE);
+    let per_page = per_page
+        .or(pagination.per_page)
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .min(max_per_page);
+
+    // Listed through a reference so shuffling and late references apply as elsewhere
+    let nested = state.config.resolve_stored_references.unwrap_or(false);
+    let reference = json!(format!("{{objects.{}}}", pagination.objects));
+    let objects = match resolve_object_references(state, &reference, nested) {
+        Ok(Value::Array(objects)) => objects,
+        Ok(_) => Vec::new(),
+        Err(error) => return error,
+    };
+
+    let total = objects.len();
+    let data: Vec<Value> = objects
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    json!({
+        "data": data,
+        "page": page,
+        "per_page": per_page,
+        "total": total
+    })
+}
+
+fn is_json_patch_request(route: &Route, headers: &HashMap<String, String>) -> bool {
+    route.method.eq_ignore_ascii_case("PATCH")
+        && headers
+            .get("content-type")
+            .is_some_and(|ct| ct.starts_with("application/json-patch+json"))
+}
+
+/// Applies an RFC 6902 patch to the stored object named by the `{id}` path parameter.
+fn apply_json_patch(
+    state: &AppState,
+    route: &Route,
+    path: &str,
+    path_params: &HashMap<String, String>,
+    payload: Option<&Value>,
+) -> Value {
+    let (Some(object_name), Some(id)) = (&route.object_name, path_params.get("id")) else {
+        return json!({"error": "JSON Patch routes need an object_name and an {id} path parameter", "status": 500});
+    };
+
+    let patch: json_patch::Patch = match payload.cloned().map(serde_json::from_value) {
+        Some(Ok(patch)) => patch,
+        Some(Err(e)) => return json!({"error": format!("Invalid JSON Patch: {e}"), "status": 422}),
+        None => return json!({"error": "Missing JSON Patch body", "status": 422}),
+    };
+
+    let mut objects = state.objects.write().unwrap();
+    let Some(stored) = objects
+        .get_mut(object_name)
+        .and_then(|list| list.iter_mut().find(|obj| &obj.id == id))
+    else {
+        return json!({"error": "Object not found", "id": id, "status": 404});
+    };
+
+    // Patch a copy so a failing operation leaves the stored object untouched
+    let mut patched = stored.data.clone();
+    if let Err(e) = json_patch::patch(&mut patched, &patch) {
+        return json!({"error": format!("Failed to apply JSON Patch: {e}"), "status": 422});
+    }
+    stored.data = patched.clone();
+
+    let mut storage = state.storage.write().unwrap();
+    if let Some(stored) = storage.get_mut(&storage_key(path, id)) {
+        *stored = patched.clone();
+    }
+
+    patched
+}
+
+/// Storage key for the object at `path`, whose last segment is its `id`, matching
+/// the key a POST to the parent path stores it under.
+fn storage_key(path: &str, id: &str) -> String {
+    let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+    format!("{parent}_{id}")
+}
+
+/// Applies an RFC 7386 merge patch from the payload to the stored object, returning the
+/// merged object or the error response.
+fn merge_object(
+    state: &AppState,
+    path: &str,
+    object_name: &str,
+    id: &str,
+    payload: Option<&Value>,
+) -> Result<Value, Value> {
+    let Some(payload) = payload else {
+        return Err(json!({"error": "Missing request body", "status": 422}));
+    };
+
+    let mut objects = state.objects.write().unwrap();
+    let Some(stored) = objects
+        .get_mut(object_name)
+        .and_then(|list| list.iter_mut().find(|obj| obj.id == id))
+    else {
+        return Err(json!({"error": "Object not found", "id": id, "status": 404}));
+    };
+    json_patch::merge(&mut stored.data, payload);
+
+    let mut storage = state.storage.write().unwrap();
+    if let Some(stored_response) = storage.get_mut(&storage_key(path, id)) {
+        *stored_response = stored.data.clone();
+    }
+
+    Ok(stored.data.clone())
+}
+
+/// Stores `data` as the object with `id`, replacing it if it already exists.
+fn put_object(state: &AppState, path: &str, object_name: &str, id: &str, mut data: Value) {
+    {
+        let mut objects = state.objects.write().unwrap();
+        let list = objects.entry(object_name.to_string()).or_default();
+        match list.iter_mut().find(|obj| obj.id == id) {
+            Some(existing) => {
+                // A replacement keeps its place in the creation order
+                if let Some(seq) = existing.data.get("_seq").cloned()
+                    && let Some(object) = data.as_object_mut()
+                {
+                    object.insert("_seq".to_string(), seq);
+                }
+                existing.data = data.clone();
+            }
+            None => {
+                state.sequence_object(&mut data);
+                list.push(StoredObject {
+                    id: id.to_string(),
+                    data: data.clone(),
+                });
+            }
+        }
+    }
+
+    state
+        .storage
+        .write()
+        .unwrap()
+        .insert(storage_key(path, id), data);
+}
+
+/// Removes the object with `id`, returning its data, or `None` when it doesn't exist.
+fn delete_object(state: &AppState, path: &str, object_name: &str, id: &str) -> Option<Value> {
+    let deleted = {
+        let mut objects = state.objects.write().unwrap();
+        let list = objects.get_mut(object_name)?;
+        let position = list.iter().position(|obj| obj.id == id)?;
+        list.remove(position).data
+    };
+
+    state
+        .storage
+        .write()
+        .unwrap()
+        .remove(&storage_key(path, id));
+    Some(deleted)
+}
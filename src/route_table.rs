@@ -0,0 +1,146 @@
+use crate::interpolation::{extract_path_parameters, is_optional_segment, match_segment};
+use crate::types::Route;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Routes compiled into a segment trie so matching a path costs one walk over its
+/// segments instead of re-splitting every configured pattern per request.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    /// Child for a whole-segment `{name}` parameter
+    param: Option<Box<Node>>,
+    /// Children for segments mixing literals and parameters, e.g. `{lat}-{lng}`
+    patterns: Vec<(String, Node)>,
+    /// Indices into `RouteTable::routes` whose pattern ends at this node
+    terminals: Vec<usize>,
+}
+
+impl RouteTable {
+    pub fn new(routes: Vec<Arc<Route>>) -> Self {
+        let mut table = RouteTable::default();
+        for route in routes {
+            table.push(route);
+        }
+        table
+    }
+
+    pub fn routes(&self) -> &[Arc<Route>] {
+        &self.routes
+    }
+
+    pub fn push(&mut self, route: Arc<Route>) {
+        let index = self.routes.len();
+        let mut node = &mut self.root;
+
+        for segment in route.path.split('/') {
+            // The route also ends before an optional segment
+            if is_optional_segment(segment) {
+                node.terminals.push(index);
+            }
+            node = if is_param_segment(segment) {
+                node.param.get_or_insert_with(Default::default)
+            } else if segment.contains('{') {
+                let index = match node.patterns.iter().position(|(p, _)| p == segment) {
+                    Some(index) => index,
+                    None => {
+                        node.patterns.push((segment.to_string(), Node::default()));
+                        node.patterns.len() - 1
+                    }
+                };
+                &mut node.patterns[index].1
+            } else {
+                node.statics.entry(segment.to_string()).or_default()
+            };
+        }
+
+        node.terminals.push(index);
+        self.routes.push(route);
+    }
+
+    /// Returns the first route, in registration order, matching `method` and `path`.
+    pub fn find(&self, method: &str, path: &str) -> Option<Arc<Route>> {
+        self.candidates(path)
+            .into_iter()
+            .map(|index| &self.routes[index])
+            .find(|route| route.method.eq_ignore_ascii_case(method))
+            .cloned()
+    }
+
+    /// Methods declared by the routes serving `path`, uppercased and deduplicated.
+    pub fn methods_for(&self, path: &str) -> Vec<String> {
+        let mut methods: Vec<String> = self
+            .candidates(path)
+            .into_iter()
+            .map(|index| self.routes[index].method.to_uppercase())
+            .collect();
+        methods.sort();
+        methods.dedup();
+        methods
+    }
+
+    /// Indices of every route whose pattern matches `path`, sorted by registration order.
+    fn candidates(&self, path: &str) -> Vec<usize> {
+        let segments: Vec<&str> = path.split('/').collect();
+        let mut matches = Vec::new();
+        self.root.collect(&segments, &mut matches);
+        matches.retain(|&index| conditions_hold(&self.routes[index], path));
+        matches.sort_unstable();
+        matches
+    }
+}
+
+impl Node {
+    fn collect(&self, segments: &[&str], matches: &mut Vec<usize>) {
+        let Some((segment, rest)) = segments.split_first() else {
+            matches.extend_from_slice(&self.terminals);
+            return;
+        };
+
+        if let Some(child) = self.statics.get(*segment) {
+            child.collect(rest, matches);
+        }
+        if let Some(child) = &self.param {
+            child.collect(rest, matches);
+        }
+        for (pattern, child) in &self.patterns {
+            if match_segment(pattern, segment).is_some() {
+                child.collect(rest, matches);
+            }
+        }
+    }
+}
+
+/// Checks the route's `param_conditions` against the parameters `path` binds.
+fn conditions_hold(route: &Route, path: &str) -> bool {
+    let Some(conditions) = &route.param_conditions else {
+        return true;
+    };
+
+    let params = extract_path_parameters(&route.path, path);
+    conditions.iter().all(|(name, condition)| {
+        params
+            .get(name)
+            .and_then(|value| value.parse::<f64>().ok())
+            .is_some_and(|value| condition.holds(value))
+    })
+}
+
+fn is_param_segment(segment: &str) -> bool {
+    segment.starts_with('{') && segment.ends_with('}') && segment.matches('{').count() == 1
+}
+
+/// True when some segment of `path` mixes parameters with literal text or is an
+/// optional `{name?}`, which the router can't express, so such routes are only
+/// served through the route table.
+pub fn has_inline_params(path: &str) -> bool {
+    path.split('/').any(|segment| {
+        (segment.contains('{') && !is_param_segment(segment)) || is_optional_segment(segment)
+    })
+}
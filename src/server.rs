@@ -0,0 +1,1357 @@
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware,
+    response::{Html, IntoResponse, Json, Response},
+    routing::{MethodFilter, MethodRouter, any, get, on, post},
+};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use encoding_rs::Encoding;
+use futures_util::StreamExt;
+use hyper::ext::ReasonPhrase;
+use serde_json::{Value, json};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::validate_route;
+use crate::interpolation::parse_query;
+use crate::lua_engine::RESPONSE_MARKER;
+use crate::multipart::{multipart_boundary, parse_multipart};
+use crate::openapi::{SWAGGER_UI_HTML, generate_spec};
+use crate::proxy::proxy_request;
+use crate::request_processing::{
+    check_payload_limits, find_matching_route, process_response, select_response_template,
+};
+use crate::route_table::has_inline_params;
+use crate::types::{
+    AppState, AsyncJob, AsyncJobConfig, CachedResponse, Expectation, FaultConfig, FlakyConfig,
+    IdempotentResponse, PreviewRequest, RecordedRequest, ReplayRequest, ResponseTemplate, Route,
+    StoredObject,
+};
+
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_STREAM_CHUNK_SIZE: usize = 16;
+const DEFAULT_STREAM_DELAY_MS: u64 = 100;
+const DEFAULT_ASYNC_JOB_DELAY_MS: u64 = 1000;
+
+/// Registers a built-in endpoint unless a configured route already uses its path, in which
+/// case the configured route wins. A catch-all built-in such as `/_echo/{*rest}` gives way to
+/// any configured route under its prefix.
+fn add_built_in(
+    app: Router<AppState>,
+    state: &AppState,
+    path: &str,
+    handler: MethodRouter<AppState>,
+) -> Router<AppState> {
+    let prefix = path.split_once("{*").map(|(prefix, _)| prefix);
+    let overridden = state
+        .routes
+        .read()
+        .unwrap()
+        .routes()
+        .iter()
+        .any(|route| match prefix {
+            Some(prefix) => route.path.starts_with(prefix),
+            None => route.path == path,
+        });
+    if overridden {
+        warn!("A configured route replaces the built-in {path} endpoint");
+        return app;
+    }
+    app.route(path, handler)
+}
+
+/// Builds the axum router for every configured route plus the built-in endpoints.
+pub fn build_router(state: AppState) -> Router {
+    let mut app = Router::new();
+
+    let strict_methods = state.config.strict_methods.unwrap_or(false);
+
+    let mut registered = HashSet::new();
+    for route in state.routes.read().unwrap().routes() {
+        let path = &route.path;
+        let method = route.method.to_uppercase();
+
+        if has_inline_params(path) {
+            continue;
+        }
+        // Routes sharing a path and method (e.g. split by `param_conditions`) share one
+        // handler, which picks between them per request. Other verbs all share `any`.
+        let key = match method.as_str() {
+            "GET" | "POST" => method.clone(),
+            _ if strict_methods => method.clone(),
+            _ => "*".to_string(),
+        };
+        if !registered.insert((path.clone(), key)) {
+            continue;
+        }
+
+        // Only the declared verb reaches the handler; anything else falls to the 405 path
+        if strict_methods && let Some(filter) = method_filter(&method) {
+            app = app.route(path, on(filter, handle_request));
+            continue;
+        }
+
+        match method.as_str() {
+            "GET" => {
+                app = app.route(path, get(handle_request));
+            }
+            "POST" => {
+                app = app.route(path, post(handle_request));
+            }
+            _ => {
+                app = app.route(path, any(handle_request));
+            }
+        }
+    }
+
+    app = app
+        .route("/state/clear", post(clear_state))
+        .route("/state/clear/lua", post(clear_lua_state_only))
+        .route("/_jobs/{id}", get(job_status))
+        .route("/state/objects/{type}/bulk", post(bulk_insert_objects));
+    app = add_built_in(app, &state, "/_preview", post(preview_request));
+    app = add_built_in(app, &state, "/_echo", any(echo_request));
+    app = add_built_in(app, &state, "/_echo/{*rest}", any(echo_request));
+    app = add_built_in(app, &state, "/livez", get(liveness));
+    app = add_built_in(app, &state, "/readyz", get(readiness));
+
+    if state.options.admin_routes {
+        app = app.route("/admin/routes", post(register_route));
+    }
+
+    if state.options.history {
+        app = app
+            .route("/_history", get(request_history))
+            .route("/_replay", post(replay_request))
+            .route("/_expect", post(register_expectation))
+            .route("/_verify", get(verify_expectations));
+    }
+
+    if state.options.stats {
+        app = app.route("/_stats", get(request_stats));
+    }
+
+    if state.options.inspect_lua {
+        app = app.route("/_lua", get(lua_scripts));
+    }
+
+    if state.options.open_api_ui {
+        app = app
+            .route("/openapi.json", get(openapi_spec))
+            .route("/docs", get(|| async { Html(SWAGGER_UI_HTML) }));
+    }
+
+    // Routes registered at runtime have no axum route of their own, so anything the
+    // static table doesn't claim is dispatched against the live route table.
+    app = app
+        .fallback(handle_request)
+        .method_not_allowed_fallback(handle_request);
+
+    if let Some(global_headers) = &state.config.global_headers {
+        let global_headers = Arc::new(global_headers.clone());
+        app = app.layer(middleware::map_response(move |response: Response| {
+            let global_headers = global_headers.clone();
+            async move { apply_global_headers(response, &global_headers) }
+        }));
+    }
+
+    app.with_state(state)
+}
+
+fn method_filter(method: &str) -> Option<MethodFilter> {
+    let method = Method::from_bytes(method.as_bytes()).ok()?;
+    MethodFilter::try_from(method).ok()
+}
+
+async fn register_route(
+    State(state): State<AppState>,
+    Json(route): Json<Route>,
+) -> (StatusCode, Json<Value>) {
+    if let Err(message) = validate_route(&route) {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({"error": message})),
+        );
+    }
+
+    let mut routes = state.routes.write().unwrap();
+
+    if routes
+        .routes()
+        .iter()
+        .any(|r| r.path == route.path && r.method.eq_ignore_ascii_case(&route.method))
+    {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "Route already exists",
+                "path": route.path,
+                "method": route.method.to_uppercase()
+            })),
+        );
+    }
+
+    let registered = json!({
+        "status": "registered",
+        "path": route.path,
+        "method": route.method.to_uppercase()
+    });
+    routes.push(Arc::new(route));
+
+    (StatusCode::CREATED, Json(registered))
+}
+
+async fn openapi_spec(State(state): State<AppState>) -> Json<Value> {
+    Json(generate_spec(&state.routes.read().unwrap()))
+}
+
+/// Reflects the request back with when it arrived and who sent it, for debugging
+/// what a client actually sends. Bodies that aren't JSON are echoed as text.
+async fn echo_request(req: Request) -> Result<Json<Value>, StatusCode> {
+    // Only present when served over TCP by the binary
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let headers: HashMap<String, String> = req
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+        .collect();
+
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).unwrap_or_else(|_| json!(String::from_utf8_lossy(&body)))
+    };
+
+    Ok(Json(json!({
+        "method": parts.method.as_str(),
+        "path": parts.uri.path(),
+        "query": parse_query(parts.uri.query()),
+        "headers": headers,
+        "body": body,
+        "received_at": chrono::Utc::now().to_rfc3339(),
+        "client_ip": client_ip,
+    })))
+}
+
+async fn liveness() -> Json<Value> {
+    Json(json!({"status": "alive"}))
+}
+
+async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    if state.ready.load(Ordering::SeqCst) {
+        (StatusCode::OK, Json(json!({"status": "ready"})))
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "starting"})),
+        )
+    }
+}
+
+/// Reports requests handled since startup or the last `/state/clear`, per route
+/// and in total, along with how many objects of each type are stored.
+async fn request_stats(State(state): State<AppState>) -> Json<Value> {
+    let objects: HashMap<String, usize> = state
+        .objects
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(object_type, list)| (object_type.clone(), list.len()))
+        .collect();
+
+    Json(json!({
+        "total_requests": state.request_count.load(Ordering::SeqCst),
+        "routes": *state.route_hits.read().unwrap(),
+        "objects": objects,
+    }))
+}
+
+/// Lists every route with a Lua script, including ones registered at runtime, so a
+/// deployment's scripts can be checked without reading its config.
+async fn lua_scripts(State(state): State<AppState>) -> Json<Value> {
+    let routes = state.routes.read().unwrap();
+    let scripts: Vec<Value> = routes
+        .routes()
+        .iter()
+        .filter_map(|route| {
+            let source = route.lua_script.as_ref()?;
+            let mut script = json!({
+                "path": route.path,
+                "method": route.method.to_uppercase(),
+            });
+            if !state.options.hide_lua_source {
+                script["source"] = json!(source);
+            }
+            Some(script)
+        })
+        .collect();
+    Json(json!({ "scripts": scripts }))
+}
+
+async fn request_history(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let history = state.history.read().unwrap();
+
+    match query.get("path") {
+        Some(path) => Json(json!({
+            "path": path,
+            "requests": history.get(path).cloned().unwrap_or_default()
+        })),
+        None => Json(json!(*history)),
+    }
+}
+
+async fn register_expectation(
+    State(state): State<AppState>,
+    Json(expectation): Json<Expectation>,
+) -> (StatusCode, Json<Value>) {
+    let mut expectations = state.expectations.write().unwrap();
+    expectations.push(expectation);
+    (
+        StatusCode::CREATED,
+        Json(json!({"registered": expectations.len()})),
+    )
+}
+
+/// Counts each expectation's matching requests in the history. A request matches when
+/// its method does and either its path or its route's pattern equals the expected path.
+async fn verify_expectations(State(state): State<AppState>) -> Json<Value> {
+    let history = state.history.read().unwrap();
+    let results: Vec<Value> = state
+        .expectations
+        .read()
+        .unwrap()
+        .iter()
+        .map(|expectation| {
+            let count = history
+                .iter()
+                .flat_map(|(pattern, requests)| requests.iter().map(move |r| (pattern, r)))
+                .filter(|(pattern, request)| {
+                    request.method.eq_ignore_ascii_case(&expectation.method)
+                        && (request.path == expectation.path || **pattern == expectation.path)
+                })
+                .count();
+            let mut result = json!(expectation);
+            result["count"] = json!(count);
+            result["satisfied"] = json!(expectation.holds(count));
+            result
+        })
+        .collect();
+
+    let satisfied = results.iter().all(|result| result["satisfied"] == true);
+    Json(json!({"satisfied": satisfied, "expectations": results}))
+}
+
+/// Dispatches a recorded request through the route table again and returns the
+/// fresh response, so a captured scenario can be reproduced.
+async fn replay_request(
+    State(state): State<AppState>,
+    Json(replay): Json<ReplayRequest>,
+) -> Response {
+    let recorded = match replay {
+        ReplayRequest::Request(request) => request,
+        ReplayRequest::Recorded { path, index } => {
+            let recorded = state
+                .history
+                .read()
+                .unwrap()
+                .get(&path)
+                .and_then(|requests| requests.get(index).cloned());
+            match recorded {
+                Some(request) => request,
+                None => {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({
+                            "error": "No recorded request at that index",
+                            "path": path,
+                            "index": index
+                        })),
+                    )
+                        .into_response();
+                }
+            }
+        }
+    };
+
+    let Ok(method) = Method::from_bytes(recorded.method.to_uppercase().as_bytes()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Invalid method", "method": recorded.method})),
+        )
+            .into_response();
+    };
+
+    match json_request(
+        method,
+        &recorded.path,
+        &recorded.headers,
+        recorded.body.as_ref(),
+    ) {
+        Ok(request) => match handle_request(State(state), request).await {
+            Ok(response) => response.into_response(),
+            Err(status) => status.into_response(),
+        },
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Invalid recorded request: {e}")})),
+        )
+            .into_response(),
+    }
+}
+
+/// Builds a request for dispatching through `handle_request`, with `body` sent as JSON.
+fn json_request(
+    method: Method,
+    uri: &str,
+    headers: &HashMap<String, String>,
+    body: Option<&Value>,
+) -> Result<Request, axum::http::Error> {
+    let mut request = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        // The body is re-serialized as JSON, so its original framing no longer applies
+        if name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("content-type")
+        {
+            continue;
+        }
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let body = match body {
+        Some(body) => {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    request.body(body)
+}
+
+/// Answers a described request as its route would, against a copy of the state, so
+/// nothing it stores, counts or records outlives the preview.
+async fn preview_request(
+    State(state): State<AppState>,
+    Json(preview): Json<PreviewRequest>,
+) -> Response {
+    let method = preview.method.as_deref().unwrap_or("GET");
+    let Ok(method) = Method::from_bytes(method.to_uppercase().as_bytes()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Invalid method", "method": method})),
+        )
+            .into_response();
+    };
+
+    let headers = preview.headers.unwrap_or_default();
+    match json_request(method, &preview.route_path, &headers, preview.body.as_ref()) {
+        Ok(request) => match handle_request(State(state.detached()), request).await {
+            Ok(response) => response.into_response(),
+            Err(status) => status.into_response(),
+        },
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("Invalid preview request: {e}")})),
+        )
+            .into_response(),
+    }
+}
+
+fn record_request(state: &AppState, route: &Route, request: RecordedRequest) {
+    let mut history = state.history.write().unwrap();
+    let entries = history.entry(route.path.clone()).or_default();
+
+    entries.push_back(request);
+    while entries.len() > state.options.history_size {
+        entries.pop_front();
+    }
+}
+
+/// Clears only the Lua state, leaving objects, storage and counters in place.
+async fn clear_lua_state_only(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    if let Some(rejection) = check_clear_token(&state, &headers) {
+        return rejection;
+    }
+
+    let cleared_lua_state = clear_lua_state(&state);
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "cleared",
+            "message": "Lua state has been cleared",
+            "cleared": {"lua_state": cleared_lua_state}
+        })),
+    )
+}
+
+/// Stores every object in the array body under `type` in one call. Objects keep
+/// their `id` field; those without one get a generated UUID.
+async fn bulk_insert_objects(
+    State(state): State<AppState>,
+    Path(object_type): Path<String>,
+    Json(body): Json<Value>,
+) -> (StatusCode, Json<Value>) {
+    let Value::Array(items) = body else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({"error": "Expected a JSON array of objects"})),
+        );
+    };
+
+    let mut stored = Vec::with_capacity(items.len());
+    for (index, mut data) in items.into_iter().enumerate() {
+        let Some(object) = data.as_object_mut() else {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({"error": "Every item must be a JSON object", "index": index})),
+            );
+        };
+
+        let id = match object.get("id") {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) => id.to_string(),
+            _ => {
+                let id = Uuid::new_v4().to_string();
+                object.insert("id".to_string(), json!(id));
+                id
+            }
+        };
+        state.sequence_object(&mut data);
+        stored.push(StoredObject { id, data });
+    }
+
+    let ids: Vec<&str> = stored.iter().map(|obj| obj.id.as_str()).collect();
+    let response = json!({"type": object_type, "count": stored.len(), "ids": ids});
+
+    state
+        .objects
+        .write()
+        .unwrap()
+        .entry(object_type)
+        .or_default()
+        .extend(stored);
+
+    (StatusCode::CREATED, Json(response))
+}
+
+/// Rejects the request unless it carries the bearer token `clear_state` requires.
+fn check_clear_token(state: &AppState, headers: &HeaderMap) -> Option<(StatusCode, Json<Value>)> {
+    let token = state.config.clear_state.as_ref()?.token.as_deref()?;
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    (presented != Some(token)).then(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "A valid bearer token is required to clear state"})),
+        )
+    })
+}
+
+/// Empties the Lua state map, returning how many keys it held.
+fn clear_lua_state(state: &AppState) -> usize {
+    let mut lua_state = state.lua_state.write().unwrap();
+    let count = lua_state.len();
+    lua_state.clear();
+    count
+}
+
+async fn clear_state(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> (StatusCode, Json<Value>) {
+    let settings = state.config.clear_state.as_ref();
+    if let Some(rejection) = check_clear_token(&state, &headers) {
+        return rejection;
+    }
+
+    let cleared_objects: serde_json::Map<String, Value> = {
+        let mut objects = state.objects.write().unwrap();
+        objects
+            .drain()
+            .map(|(object_type, list)| (object_type, json!(list.len())))
+            .collect()
+    };
+    let cleared_storage = {
+        let mut storage = state.storage.write().unwrap();
+        let count = storage.len();
+        storage.clear();
+        count
+    };
+    let cleared_lua_state = clear_lua_state(&state);
+    state.reset_counters();
+
+    let cleared = json!({
+        "objects": cleared_objects,
+        "storage": cleared_storage,
+        "lua_state": cleared_lua_state
+    });
+
+    let mut body = settings
+        .and_then(|s| s.response.clone())
+        .unwrap_or_else(|| {
+            json!({
+                "status": "cleared",
+                "message": "All stored state has been cleared"
+            })
+        });
+    if let Some(body) = body.as_object_mut() {
+        body.insert("cleared".to_string(), cleared);
+    }
+
+    (StatusCode::OK, Json(body))
+}
+
+async fn handle_request(
+    State(state): State<AppState>,
+    req: Request,
+) -> Result<impl IntoResponse, StatusCode> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    let headers: HashMap<String, String> = req
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+        .collect();
+
+    let (parts, body) = req.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    state.request_count.fetch_add(1, Ordering::SeqCst);
+    let route = find_matching_route(&state.routes.read().unwrap(), method.as_ref(), &path);
+
+    let Some(route) = route else {
+        let allowed = state.routes.read().unwrap().methods_for(&path);
+        let strict_methods = state.config.strict_methods.unwrap_or(false);
+
+        if let Some(upstream) = &state.config.proxy_fallback
+            && (!strict_methods || allowed.is_empty())
+        {
+            return Ok(proxy_request(upstream, method, &parts.uri, &parts.headers, body).await);
+        }
+        if !allowed.is_empty() {
+            let allow = allowed.join(", ");
+            return Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, allow)]).into_response());
+        }
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    if state.options.stats {
+        let key = format!("{} {}", route.method.to_uppercase(), route.path);
+        *state.route_hits.write().unwrap().entry(key).or_default() += 1;
+    }
+
+    let payload = if (method == Method::POST
+        || method == Method::PUT
+        || method == Method::PATCH
+        || method == Method::DELETE)
+        && !body.is_empty()
+    {
+        match multipart_boundary(headers.get("content-type").map(String::as_str)) {
+            Some(boundary) => Some(
+                parse_multipart(body.clone(), boundary)
+                    .await
+                    .map_err(|_| StatusCode::BAD_REQUEST)?,
+            ),
+            None => match serde_json::from_slice::<Value>(&body) {
+                Ok(payload) => Some(payload),
+                Err(e) if state.options.debug => return Ok(json_parse_error(&body, &e)),
+                Err(_) => return Err(StatusCode::BAD_REQUEST),
+            },
+        }
+    } else {
+        None
+    };
+
+    // Rejected before anything walks the payload, so oversized input costs no more than parsing
+    if let Some(error) = payload
+        .as_ref()
+        .and_then(|payload| check_payload_limits(&route, payload))
+    {
+        return Ok((StatusCode::BAD_REQUEST, Json(json!({"error": error}))).into_response());
+    }
+
+    if state.options.history {
+        let recorded = RecordedRequest {
+            method: method.to_string(),
+            path: path.clone(),
+            headers: headers.clone(),
+            body: payload.clone(),
+        };
+        record_request(&state, &route, recorded);
+    }
+
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .filter(|_| method == Method::POST)
+        .map(|key| (path.clone(), key.clone()));
+    if let Some(key) = &idempotency_key
+        && let Some(replayed) = replay_idempotent(&state, key)
+    {
+        return Ok(replayed);
+    }
+
+    let cache_key = route
+        .cache_ttl_ms
+        .map(|_| request_signature(&method, &path, parts.uri.query(), &body));
+    if let Some(key) = &cache_key
+        && let Some(cached) = replay_cached(&state, &route, key)
+    {
+        let template = select_response_template(&route, &path, &headers);
+        let status =
+            StatusCode::from_u16(cached.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response =
+            render_route_body(&route, status, &cached.body, template, state.options.pretty);
+        apply_headers(&mut response, &cached.headers);
+        response
+            .headers_mut()
+            .insert("x-cache", HeaderValue::from_static("HIT"));
+        let response = apply_etag(
+            &state,
+            &route,
+            &method,
+            &headers,
+            template,
+            &cached.body,
+            response,
+        );
+        return Ok(stream_if_configured(&route, response).await);
+    }
+
+    let fault_requested = route.fault_header.as_ref().is_none_or(|trigger| {
+        headers
+            .get(&trigger.name.to_ascii_lowercase())
+            .is_some_and(|value| trigger.value.as_ref().is_none_or(|wanted| value == wanted))
+    });
+    if let Some(fault) = &route.fault
+        && fault_requested
+        && rand::random::<f64>() < fault.rate.unwrap_or(1.0)
+    {
+        return Ok(fault_response(fault));
+    }
+
+    if let Some(flaky) = &route.flaky
+        && let Some(key) = &idempotency_key
+        && fails_attempt(&state, flaky, key)
+    {
+        return Ok(flaky_response(flaky));
+    }
+
+    let raw_body = (!body.is_empty()).then(|| String::from_utf8_lossy(&body));
+    let query = parse_query(parts.uri.query());
+    let processing = async {
+        if let Some(delay) = startup_delay(&state, &route) {
+            tokio::time::sleep(delay).await;
+        }
+        process_response(
+            &state,
+            &route,
+            &path,
+            payload.as_ref(),
+            raw_body.as_deref(),
+            &headers,
+            &query,
+        )
+        .await
+    };
+    let response = match route.timeout_ms {
+        Some(timeout_ms) => {
+            match tokio::time::timeout(Duration::from_millis(timeout_ms), processing).await {
+                Ok(response) => response,
+                Err(_) => return Ok(timeout_response(timeout_ms)),
+            }
+        }
+        None => processing.await,
+    };
+
+    let template = select_response_template(&route, &path, &headers);
+
+    if let Some(job) = &route.async_job {
+        return Ok(start_async_job(&state, job, &response, &headers));
+    }
+
+    let (status, body, extra_headers) = if response.get(RESPONSE_MARKER).is_some() {
+        // Responses built with the Lua `response()` helper
+        let status = response["status"]
+            .as_u64()
+            .and_then(|code| StatusCode::from_u16(code as u16).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let headers: HashMap<String, String> = response
+            .get("headers")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| {
+                // Numbers are accepted so computed values like Retry-After need no tostring()
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    _ => return None,
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+        (status, &response["body"], headers)
+    } else if let Some(status_code) = response.get("status").and_then(Value::as_u64) {
+        // Lua script status (top-level status field)
+        let status =
+            StatusCode::from_u16(status_code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (
+            status,
+            response.get("body").unwrap_or(&response),
+            HashMap::new(),
+        )
+    } else {
+        // Traditional template status
+        let status = template
+            .and_then(|t| t.status)
+            .map(|s| StatusCode::from_u16(s).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+            .unwrap_or(StatusCode::OK);
+        (status, &response, HashMap::new())
+    };
+    let body = &*recase_body(&state, &route, body);
+
+    if let Some(key) = idempotency_key {
+        remember_idempotent(&state, key, status, body);
+    }
+    let mut rendered = render_route_body(&route, status, body, template, state.options.pretty);
+    apply_headers(&mut rendered, &extra_headers);
+    if let Some(key) = cache_key {
+        remember_cached(&state, key, status, body, extra_headers);
+        rendered
+            .headers_mut()
+            .insert("x-cache", HeaderValue::from_static("MISS"));
+    }
+    let rendered = apply_etag(&state, &route, &method, &headers, template, body, rendered);
+    Ok(stream_if_configured(&route, rendered).await)
+}
+
+/// Tags a successful response with an ETag derived from its body when the route (or
+/// the config) enables `etag`, and swaps it for an empty 304 when the request's
+/// `If-None-Match` already names that tag on a GET or HEAD.
+fn apply_etag(
+    state: &AppState,
+    route: &Route,
+    method: &Method,
+    headers: &HashMap<String, String>,
+    template: Option<&ResponseTemplate>,
+    body: &Value,
+    mut response: Response,
+) -> Response {
+    let enabled = route.etag.or(state.config.etag).unwrap_or(false);
+    if !enabled || !response.status().is_success() {
+        return response;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    body.to_string().hash(&mut hasher);
+    template
+        .and_then(|t| t.body_base64.as_ref())
+        .hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    let Ok(etag_value) = HeaderValue::from_str(&etag) else {
+        return response;
+    };
+
+    // Weak comparison, as If-None-Match calls for
+    let cacheable = *method == Method::GET || *method == Method::HEAD;
+    let matches = cacheable
+        && headers.get("if-none-match").is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    if matches {
+        response = StatusCode::NOT_MODIFIED.into_response();
+    }
+    response.headers_mut().insert(header::ETAG, etag_value);
+    response
+}
+
+/// Keeps the response as the result of a new job and answers 202 with its status URL.
+fn start_async_job(
+    state: &AppState,
+    job: &AsyncJobConfig,
+    response: &Value,
+    headers: &HashMap<String, String>,
+) -> Response {
+    // Helper and Lua status responses wrap the body; templates return it directly
+    let body = match response.get("status") {
+        Some(status) if status.is_u64() => response.get("body").unwrap_or(response),
+        _ => response,
+    };
+
+    let id = Uuid::new_v4().to_string();
+    let delay = Duration::from_millis(job.delay_ms.unwrap_or(DEFAULT_ASYNC_JOB_DELAY_MS));
+    state.jobs.write().unwrap().insert(
+        id.clone(),
+        AsyncJob {
+            ready_at: Instant::now() + delay,
+            body: body.clone(),
+        },
+    );
+
+    let location = format!("/_jobs/{id}");
+    let mut response = (
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location.clone())],
+        Json(json!({"id": id, "status": "processing", "location": location})),
+    )
+        .into_response();
+    if headers
+        .get("prefer")
+        .is_some_and(|prefer| prefer.contains("respond-async"))
+    {
+        response.headers_mut().insert(
+            HeaderName::from_static("preference-applied"),
+            HeaderValue::from_static("respond-async"),
+        );
+    }
+    response
+}
+
+/// Reports an async job as processing until its delay has passed, then as done
+/// with the deferred response body as `result`.
+async fn job_status(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> (StatusCode, Json<Value>) {
+    let jobs = state.jobs.read().unwrap();
+    let Some(job) = jobs.get(&id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Job not found", "id": id})),
+        );
+    };
+
+    if Instant::now() < job.ready_at {
+        (
+            StatusCode::OK,
+            Json(json!({"id": id, "status": "processing"})),
+        )
+    } else {
+        (
+            StatusCode::OK,
+            Json(json!({"id": id, "status": "done", "result": job.body})),
+        )
+    }
+}
+
+/// Re-sends a rendered response in fixed-size chunks with a pause before each one
+/// when the route has a `stream` config.
+async fn stream_if_configured(route: &Route, response: Response) -> Response {
+    let Some(stream) = &route.stream else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let chunk_size = stream
+        .chunk_size
+        .unwrap_or(DEFAULT_STREAM_CHUNK_SIZE)
+        .max(1);
+    let delay = Duration::from_millis(stream.delay_ms.unwrap_or(DEFAULT_STREAM_DELAY_MS));
+    let chunks: Vec<Bytes> = bytes
+        .chunks(chunk_size)
+        .map(Bytes::copy_from_slice)
+        .collect();
+
+    let chunks = futures_util::stream::iter(chunks).then(move |chunk| async move {
+        tokio::time::sleep(delay).await;
+        Ok::<_, Infallible>(chunk)
+    });
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
+
+/// Picks the route's cold or warm latency, marking the route warm on its first request.
+fn startup_delay(state: &AppState, route: &Route) -> Option<Duration> {
+    if route.cold_start_ms.is_none() && route.warm_ms.is_none() {
+        return None;
+    }
+
+    let key = format!("{} {}", route.method.to_uppercase(), route.path);
+    let first_request = state.warmed_routes.write().unwrap().insert(key);
+    let delay_ms = if first_request {
+        route.cold_start_ms.or(route.warm_ms)
+    } else {
+        route.warm_ms
+    };
+
+    delay_ms.map(Duration::from_millis)
+}
+
+/// Returns the stored response for a repeated `Idempotency-Key`, if it hasn't expired.
+fn replay_idempotent(state: &AppState, key: &(String, String)) -> Option<Response> {
+    let ttl = Duration::from_secs(
+        state
+            .config
+            .idempotency_ttl_secs
+            .unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECS),
+    );
+
+    let mut idempotency = state.idempotency.write().unwrap();
+    let stored = idempotency.get(key)?;
+    if stored.stored_at.elapsed() > ttl {
+        idempotency.remove(key);
+        return None;
+    }
+
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = (status, Json(stored.body.clone())).into_response();
+    response
+        .headers_mut()
+        .insert("idempotent-replayed", HeaderValue::from_static("true"));
+    Some(response)
+}
+
+/// Identifies requests a `cache_ttl_ms` route answers alike: same method, path, query and body.
+fn request_signature(method: &Method, path: &str, query: Option<&str>, body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!(
+        "{method} {path}?{} {:016x}",
+        query.unwrap_or_default(),
+        hasher.finish()
+    )
+}
+
+/// Returns the cached response for `key`, if the route's `cache_ttl_ms` hasn't passed since.
+fn replay_cached(state: &AppState, route: &Route, key: &str) -> Option<CachedResponse> {
+    let ttl = Duration::from_millis(route.cache_ttl_ms?);
+    let mut cache = state.response_cache.write().unwrap();
+    let cached = cache.get(key)?;
+    if cached.stored_at.elapsed() > ttl {
+        cache.remove(key);
+        return None;
+    }
+    Some(cached.clone())
+}
+
+fn remember_cached(
+    state: &AppState,
+    key: String,
+    status: StatusCode,
+    body: &Value,
+    headers: HashMap<String, String>,
+) {
+    state.response_cache.write().unwrap().insert(
+        key,
+        CachedResponse {
+            status: status.as_u16(),
+            body: body.clone(),
+            headers,
+            stored_at: Instant::now(),
+        },
+    );
+}
+
+fn remember_idempotent(state: &AppState, key: (String, String), status: StatusCode, body: &Value) {
+    state.idempotency.write().unwrap().insert(
+        key,
+        IdempotentResponse {
+            status: status.as_u16(),
+            body: body.clone(),
+            stored_at: Instant::now(),
+        },
+    );
+}
+
+/// Rewrites object keys to the route's `response_case`, falling back to the config-wide one.
+fn recase_body<'a>(state: &AppState, route: &Route, body: &'a Value) -> Cow<'a, Value> {
+    match route
+        .response_case
+        .as_deref()
+        .or(state.config.response_case.as_deref())
+    {
+        Some(case) => Cow::Owned(recase_keys(body, case)),
+        None => Cow::Borrowed(body),
+    }
+}
+
+fn recase_keys(value: &Value, case: &str) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (convert_case(key, case), recase_keys(value, case)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(|v| recase_keys(v, case)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Splits a key into words at `_`, `-` and lower-to-upper boundaries, then joins them
+/// in `case`: camel (`orderId`), pascal (`OrderId`) or snake (`order_id`).
+fn convert_case(key: &str, case: &str) -> String {
+    let mut words: Vec<String> = Vec::new();
+    let mut previous_lower = false;
+    for c in key.chars() {
+        if c == '_' || c == '-' {
+            words.push(String::new());
+            previous_lower = false;
+            continue;
+        }
+        if words.is_empty() || (c.is_uppercase() && previous_lower) {
+            words.push(String::new());
+        }
+        previous_lower = c.is_lowercase() || c.is_ascii_digit();
+        words.last_mut().unwrap().extend(c.to_lowercase());
+    }
+    let words = words.into_iter().filter(|w| !w.is_empty());
+
+    match case {
+        "snake" => words.collect::<Vec<_>>().join("_"),
+        _ => words
+            .enumerate()
+            .map(|(i, word)| {
+                if i == 0 && case == "camel" {
+                    return word;
+                }
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            })
+            .collect(),
+    }
+}
+
+/// Streams an array body as newline-delimited JSON, one element per line, when the
+/// route sets `format: ndjson`. Other bodies, such as error objects, are sent as JSON.
+fn render_route_body(
+    route: &Route,
+    status: StatusCode,
+    body: &Value,
+    template: Option<&ResponseTemplate>,
+    pretty: bool,
+) -> Response {
+    if route.format.as_deref() == Some("ndjson")
+        && let Value::Array(items) = body
+    {
+        let lines = futures_util::stream::iter(items.clone())
+            .map(|item| Ok::<_, Infallible>(Bytes::from(format!("{item}\n"))));
+        return (
+            status,
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(lines),
+        )
+            .into_response();
+    }
+    render_body(status, body, template, pretty)
+}
+
+/// Serializes a response body as JSON, applying the template's charset and reason phrase.
+fn render_body(
+    status: StatusCode,
+    body: &Value,
+    template: Option<&ResponseTemplate>,
+    pretty: bool,
+) -> Response {
+    let mut response = encode_body(status, body, template, pretty);
+    if let Some(content_type) = template.and_then(|t| t.content_type.as_deref()) {
+        match HeaderValue::from_str(content_type) {
+            Ok(value) => {
+                response.headers_mut().insert(header::CONTENT_TYPE, value);
+            }
+            Err(_) => warn!("Invalid content type '{content_type}', using the default"),
+        }
+    }
+
+    // The reason belongs to the template's status; skip it when a fault or pattern overrode that
+    if let Some(template) = template
+        && let Some(reason) = &template.reason
+        && template.status.unwrap_or(200) == status.as_u16()
+    {
+        match ReasonPhrase::try_from(reason.clone()) {
+            Ok(reason) => {
+                response.extensions_mut().insert(reason);
+            }
+            Err(_) => warn!("Invalid reason phrase '{reason}', using the standard one"),
+        }
+    }
+
+    response
+}
+
+/// Serializes a response body as JSON, transcoding it when the template sets a charset.
+/// A template's `body_base64` is sent as raw bytes instead.
+fn encode_body(
+    status: StatusCode,
+    body: &Value,
+    template: Option<&ResponseTemplate>,
+    pretty: bool,
+) -> Response {
+    if let Some(encoded) = template.and_then(|t| t.body_base64.as_deref()) {
+        match BASE64_STANDARD.decode(encoded) {
+            Ok(bytes) => {
+                return (
+                    status,
+                    [(header::CONTENT_TYPE, "application/octet-stream")],
+                    bytes,
+                )
+                    .into_response();
+            }
+            Err(e) => warn!("Invalid body_base64 ({e}), sending the JSON body"),
+        }
+    }
+
+    if let Some(label) = template.and_then(|t| t.charset.as_deref()) {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => {
+                let serialized = serialize_body(body, pretty);
+                let (bytes, _, had_unmappable) = encoding.encode(&serialized);
+                if had_unmappable {
+                    warn!("Response body has characters not representable in {label}");
+                }
+
+                let content_type = format!("application/json; charset={label}");
+                return (
+                    status,
+                    [(header::CONTENT_TYPE, content_type)],
+                    bytes.into_owned(),
+                )
+                    .into_response();
+            }
+            None => warn!("Unknown response charset '{label}', sending UTF-8"),
+        }
+    }
+
+    if pretty {
+        return (
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            serialize_body(body, true),
+        )
+            .into_response();
+    }
+
+    (status, Json(body)).into_response()
+}
+
+fn serialize_body(body: &Value, pretty: bool) -> String {
+    if pretty {
+        serde_json::to_string_pretty(body).unwrap_or_else(|_| body.to_string())
+    } else {
+        body.to_string()
+    }
+}
+
+/// Counts an attempt for the request's `Idempotency-Key`, returning true while the key
+/// is still within its `flaky` failures.
+fn fails_attempt(state: &AppState, flaky: &FlakyConfig, key: &(String, String)) -> bool {
+    let mut attempts = state.key_attempts.write().unwrap();
+    let seen = attempts.entry(key.clone()).or_default();
+    *seen = seen.saturating_add(1);
+    *seen <= flaky.failures.unwrap_or(1)
+}
+
+fn flaky_response(flaky: &FlakyConfig) -> Response {
+    let status = flaky
+        .status
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let body = flaky.body.clone().unwrap_or_else(
+        || json!({"error": "Transient failure, retry with the same Idempotency-Key"}),
+    );
+    (status, Json(body)).into_response()
+}
+
+/// The `--debug` answer to a body that isn't valid JSON: serde_json's message and where
+/// in the body it gave up, as a line, column and byte offset.
+fn json_parse_error(body: &[u8], error: &serde_json::Error) -> Response {
+    // serde_json reports 1-based lines and byte columns; the offset counts from 0
+    let line_start: usize = body
+        .split_inclusive(|&b| b == b'\n')
+        .take(error.line().saturating_sub(1))
+        .map(<[u8]>::len)
+        .sum();
+    let offset = (line_start + error.column().saturating_sub(1)).min(body.len());
+    let body = json!({
+        "error": "Invalid JSON body",
+        "message": error.to_string(),
+        "line": error.line(),
+        "column": error.column(),
+        "offset": offset,
+    });
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+/// Answers 504 for a route whose `timeout_ms` ran out before its response was ready.
+fn timeout_response(timeout_ms: u64) -> Response {
+    let body = json!({"error": format!("Request timed out after {timeout_ms}ms")});
+    (StatusCode::GATEWAY_TIMEOUT, Json(body)).into_response()
+}
+
+fn fault_response(fault: &FaultConfig) -> Response {
+    let status = StatusCode::from_u16(fault.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let body = fault
+        .body
+        .clone()
+        .unwrap_or_else(|| json!({"error": "Injected fault"}));
+
+    let mut response = (status, Json(body)).into_response();
+    if let Some(headers) = &fault.headers {
+        apply_headers(&mut response, headers);
+    }
+    response
+}
+
+/// Adds the configured global headers to every response that doesn't already set
+/// them, so headers chosen by a route take precedence.
+fn apply_global_headers(mut response: Response, headers: &HashMap<String, String>) -> Response {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) && !response.headers().contains_key(&name)
+        {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Sets each configured header on the response, skipping names or values that aren't valid HTTP.
+fn apply_headers(response: &mut Response, headers: &HashMap<String, String>) {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+}
@@ -1,12 +1,62 @@
+use crate::route_table::RouteTable;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub routes: Vec<Route>,
     pub defaults: Option<HashMap<String, Value>>,
+    /// YAML or JSON file of further defaults, loaded at startup; `defaults` set here win
+    pub defaults_file: Option<String>,
+    /// Shared constants available to every template as `{context.name}`
+    pub context: Option<HashMap<String, Value>>,
+    /// Objects preloaded into the cross-reference store at startup
+    pub seed: Option<Vec<SeedObject>>,
+    /// Upstream base URL that requests matching no route are forwarded to
+    pub proxy_fallback: Option<String>,
+    /// Keep `{objects...}` references in stored objects and resolve them on every read
+    pub resolve_stored_references: Option<bool>,
+    /// Headers added to every response, unless the response already sets them
+    pub global_headers: Option<HashMap<String, String>>,
+    /// Give objects lacking a projected field a `null` in `{objects.type.field}` lists
+    pub null_missing_fields: Option<bool>,
+    /// Add an increasing `_seq` field to every stored object, in creation order
+    pub sequence_objects: Option<bool>,
+    /// Protection and response body for `POST /state/clear`
+    pub clear_state: Option<ClearStateConfig>,
+    /// Answer methods a path doesn't declare with 405, even when `proxy_fallback` is set
+    pub strict_methods: Option<bool>,
+    /// How long a POST response is replayed for repeats of its `Idempotency-Key` (default: 24h)
+    pub idempotency_ttl_secs: Option<u64>,
+    /// Key case every response body is rewritten to: camel, snake or pascal
+    pub response_case: Option<String>,
+    /// Send ETags on successful responses and answer a matching `If-None-Match` with 304
+    pub etag: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClearStateConfig {
+    /// Bearer token required in the `Authorization` header
+    pub token: Option<String>,
+    /// Replaces the default response body; objects also get the `cleared` counts
+    pub response: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedObject {
+    /// Object type the seed is stored under (e.g., "orders")
+    #[serde(rename = "type")]
+    pub object_type: String,
+    pub id: String,
+    pub data: Value,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,18 +64,227 @@ pub struct Route {
     pub path: String,
     pub method: String,
     pub response: Option<ResponseTemplate>,
+    /// Alternative templates keyed by media type, chosen by the request's Accept header
+    pub responses_by_accept: Option<HashMap<String, ResponseTemplate>>,
+    /// Templates keyed by a path parameter value, used instead of `response` when a
+    /// parameter has that value (e.g. a canned profile for `/users/me`)
+    pub param_cases: Option<HashMap<String, ResponseTemplate>>,
     pub variables: Option<HashMap<String, VariableConfig>>,
     pub lua_script: Option<String>,
     /// Name for this object type (e.g., "orders", "users")
     pub object_name: Option<String>,
     /// Whether to store this response for cross-references
     pub store_object: Option<bool>,
+    /// JSON Schema objects must match before POST or PUT stores them; others get a 422
+    pub object_schema: Option<Value>,
+    /// Deepest array/object nesting a request payload may have before it's rejected with a 400
+    pub max_payload_depth: Option<usize>,
+    /// Most object fields, counted at every level, a request payload may have before a 400
+    pub max_payload_fields: Option<usize>,
+    /// How POST derives the stored object's id: uuid, sequence, payload_field or hash.
+    /// Without it, objects are stored under the generated `id` variable, or a random UUID
+    pub id_strategy: Option<String>,
+    /// Payload field the `payload_field` strategy takes the id from (default: "id")
+    pub id_field: Option<String>,
+    /// Injects a canned failure instead of the normal response
+    pub fault: Option<FaultConfig>,
+    /// Only inject `fault` into requests carrying this header
+    pub fault_header: Option<FaultHeader>,
+    /// Fails the first attempts of each POST `Idempotency-Key`, succeeding on retry
+    pub flaky: Option<FlakyConfig>,
+    /// Statuses served in turn, each for `count` requests, cycling forever
+    pub pattern_status: Option<Vec<StatusStep>>,
+    /// Picks each response's status at random by relative weight (e.g., 200: 0.8, 500: 0.2)
+    pub status_weights: Option<HashMap<u16, f64>>,
+    /// Replaces the route's normal body when `status_weights` picks that status
+    pub status_bodies: Option<HashMap<u16, Value>>,
+    /// Sends the body in delayed chunks instead of all at once
+    pub stream: Option<StreamConfig>,
+    /// Body format: json (default) or ndjson, which streams array bodies one element per line
+    pub format: Option<String>,
+    /// Lists stored objects a page at a time, wrapped in a `data`/`page`/`per_page`/`total`
+    /// envelope, instead of using the response template
+    pub paginate: Option<PaginationConfig>,
+    /// Key case of the response body: camel, snake or pascal (overrides the config-wide setting)
+    pub response_case: Option<String>,
+    /// Send an ETag and answer a matching `If-None-Match` with 304 (overrides the
+    /// config-wide setting)
+    pub etag: Option<bool>,
+    /// Delay before the first response after startup or `/state/clear`, in milliseconds
+    pub cold_start_ms: Option<u64>,
+    /// Delay before every later response, in milliseconds
+    pub warm_ms: Option<u64>,
+    /// Serves identical requests (method, path, query and body) from a cache for this long,
+    /// in milliseconds, instead of generating a new response
+    pub cache_ttl_ms: Option<u64>,
+    /// Abandons the response with a 504 when delays and processing take longer, in milliseconds
+    pub timeout_ms: Option<u64>,
+    /// Switches to a degraded response once the server has handled enough requests
+    pub degraded: Option<DegradedConfig>,
+    /// Regexes that named payload fields must match in full, checked before responding
+    pub field_patterns: Option<HashMap<String, String>>,
+    /// Numeric bounds on path parameters; the route only matches when all hold
+    pub param_conditions: Option<HashMap<String, NumericCondition>>,
+    /// Resolve `{objects...}` strings in the Lua script's result before responding
+    pub resolve_refs_in_lua_output: Option<bool>,
+    /// Answers 202 with a job status URL instead of the response, which the job
+    /// reports once it finishes
+    pub async_job: Option<AsyncJobConfig>,
+    /// `field_patterns` compiled on first use
+    #[serde(skip)]
+    field_regexes: OnceLock<Result<Vec<(String, Regex)>, String>>,
+    /// `object_schema` compiled on first use
+    #[serde(skip)]
+    schema_validator: OnceLock<Result<Arc<jsonschema::Validator>, String>>,
+}
+
+impl Route {
+    /// Whether POST responses are stored: named object types are unless `store_object`
+    /// is false, other routes only when it is true.
+    pub fn stores_objects(&self) -> bool {
+        self.store_object.unwrap_or(self.object_name.is_some())
+    }
+
+    /// Whether POST has a way to pick the stored object's id: an `id` variable or an
+    /// `id_strategy`. Without one, objects are stored under a generated UUID.
+    pub fn has_id_source(&self) -> bool {
+        self.id_strategy.is_some()
+            || self
+                .variables
+                .as_ref()
+                .is_some_and(|variables| variables.contains_key("id"))
+    }
+
+    /// The `field_patterns` regexes, sorted by field and anchored to match whole values.
+    /// They're compiled once; the error names the first field with an invalid pattern.
+    pub fn field_regexes(&self) -> Result<&[(String, Regex)], &str> {
+        self.field_regexes
+            .get_or_init(|| {
+                let mut patterns: Vec<(&String, &String)> =
+                    self.field_patterns.iter().flatten().collect();
+                patterns.sort();
+                patterns
+                    .into_iter()
+                    .map(|(field, pattern)| {
+                        Regex::new(&format!("^(?:{pattern})$"))
+                            .map(|regex| (field.clone(), regex))
+                            .map_err(|e| format!("invalid pattern for field '{field}': {e}"))
+                    })
+                    .collect()
+            })
+            .as_deref()
+            .map_err(String::as_str)
+    }
+
+    /// The compiled `object_schema`, if the route has one. It's compiled once.
+    pub fn schema_validator(&self) -> Option<Result<&jsonschema::Validator, &str>> {
+        let schema = self.object_schema.as_ref()?;
+        let compiled = self.schema_validator.get_or_init(|| {
+            jsonschema::validator_for(schema)
+                .map(Arc::new)
+                .map_err(|e| e.to_string())
+        });
+        Some(compiled.as_deref().map_err(String::as_str))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsyncJobConfig {
+    /// How long the job stays "processing", in milliseconds (default: 1000)
+    pub delay_ms: Option<u64>,
+}
+
+/// Bounds a numeric path parameter must satisfy. Non-numeric values never match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NumericCondition {
+    pub lt: Option<f64>,
+    pub lte: Option<f64>,
+    pub gt: Option<f64>,
+    pub gte: Option<f64>,
+}
+
+impl NumericCondition {
+    pub fn holds(&self, value: f64) -> bool {
+        self.lt.is_none_or(|bound| value < bound)
+            && self.lte.is_none_or(|bound| value <= bound)
+            && self.gt.is_none_or(|bound| value > bound)
+            && self.gte.is_none_or(|bound| value >= bound)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DegradedConfig {
+    /// Requests the whole server handles before this route degrades
+    pub after_requests: u64,
+    pub response: ResponseTemplate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamConfig {
+    /// Bytes per chunk (default: 16)
+    pub chunk_size: Option<usize>,
+    /// Pause before each chunk in milliseconds (default: 100)
+    pub delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaginationConfig {
+    /// Object type listed (e.g., "orders")
+    pub objects: String,
+    /// Page size when the request has no `per_page` query parameter (default: 10)
+    pub per_page: Option<usize>,
+    /// Largest `per_page` a request may ask for (default: 100)
+    pub max_per_page: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusStep {
+    pub status: u16,
+    pub count: u64,
+    /// Replaces the route's normal response body while this step is active
+    pub body: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultConfig {
+    pub status: u16,
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<Value>,
+    /// Probability (0.0 - 1.0) that a request gets the fault; always when omitted
+    pub rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FaultHeader {
+    /// Header name, matched case-insensitively
+    pub name: String,
+    /// Value the header must have; any value triggers the fault when omitted
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlakyConfig {
+    /// Attempts per key that fail before one succeeds (default: 1)
+    pub failures: Option<u32>,
+    /// Status of the failed attempts (default: 500)
+    pub status: Option<u16>,
+    pub body: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResponseTemplate {
     pub status: Option<u16>,
+    /// Custom reason phrase for the status line (HTTP/1.x only)
+    pub reason: Option<String>,
+    #[serde(default)]
     pub body: Value,
+    /// Raw bytes sent instead of `body`, base64-encoded (e.g., an image or PDF download)
+    pub body_base64: Option<String>,
+    /// Content-Type of the response (default: application/json, or
+    /// application/octet-stream for `body_base64`)
+    pub content_type: Option<String>,
+    /// Character set the serialized body is transcoded to (e.g., "ISO-8859-1")
+    pub charset: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,9 +294,32 @@ pub struct VariableConfig {
     pub default: Option<Value>,
     // String type parameters
     pub prefix: Option<String>,
-    // Integer type parameters
-    pub min: Option<i64>,
-    pub max: Option<i64>,
+    // Range parameters: integers for `integer`, numbers for `float`, ISO dates for `date`
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    /// Decimal places a `float` is rounded to
+    pub precision: Option<u32>,
+    /// Chance (0.0 - 1.0) a `boolean` comes out true (default: 0.5)
+    pub true_probability: Option<f64>,
+    // Date and timestamp parameters (chrono strftime syntax; dates default to `%Y-%m-%d`,
+    // timestamps to RFC 3339)
+    pub format: Option<String>,
+    /// Chance (0.0 - 1.0) the field appears at all; when it doesn't, keys whose
+    /// value is exactly this variable's placeholder are dropped from the response
+    pub present_probability: Option<f64>,
+    /// Derive this variable from another generated variable instead of generating it
+    pub from: Option<String>,
+    /// How a derived variable transforms its source: copy (default), lowercase,
+    /// uppercase or slug
+    pub expr: Option<String>,
+    /// Payload field a `payload_ref` variable copies, falling back to `default`
+    pub field: Option<String>,
+    /// First value of a sequence; an RFC 3339 timestamp for `sequence_time`
+    pub start: Option<Value>,
+    /// Increment between consecutive values of a sequence; seconds for `sequence_time`
+    pub step: Option<i64>,
+    /// Seconds a `timestamp` is shifted from the current time; negative is in the past
+    pub offset_seconds: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,13 +334,307 @@ pub struct LuaRequestContext {
     pub path: String,
     pub headers: HashMap<String, String>,
     pub body: Option<Value>,
+    /// Request body exactly as received, for signature checks over the raw bytes
+    pub raw_body: Option<String>,
     pub path_params: HashMap<String, String>,
+    /// Query parameters; repeated keys hold arrays
+    pub query: HashMap<String, Value>,
+}
+
+/// Runtime switches set from the command line rather than the config file.
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// Enables `POST /admin/routes` for registering routes at runtime
+    pub admin_routes: bool,
+    /// Records matched requests and exposes them via `GET /_history`
+    pub history: bool,
+    /// Number of requests kept per route when history is enabled
+    pub history_size: usize,
+    /// Counts requests per route and exposes the totals via `GET /_stats`
+    pub stats: bool,
+    /// Resolves `{env.NAME}` placeholders from the server's environment per request
+    pub allow_env: bool,
+    /// Attaches generation warnings to responses under `_warnings`
+    pub debug: bool,
+    /// Indents JSON response bodies for readability
+    pub pretty: bool,
+    /// Serves the OpenAPI spec at `GET /openapi.json` and Swagger UI at `GET /docs`
+    pub open_api_ui: bool,
+    /// Deepest nesting allowed while generating a response (default: 128)
+    pub max_depth: Option<usize>,
+    /// Shuffles the objects `{objects...}` references list, so clients can't rely on order
+    pub shuffle_objects: bool,
+    /// Seeds the shuffle so its orders repeat from run to run
+    pub seed: Option<u64>,
+    /// Serve the configured Lua scripts at `GET /_lua`
+    pub inspect_lua: bool,
+    /// Leave the script source out of `GET /_lua`, listing only the routes
+    pub hide_lua_source: bool,
+}
+
+/// A POST response remembered for replay to requests repeating its `Idempotency-Key`.
+#[derive(Debug, Clone)]
+pub struct IdempotentResponse {
+    pub status: u16,
+    pub body: Value,
+    pub stored_at: Instant,
+}
+
+/// A response served again to identical requests within the route's `cache_ttl_ms`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub body: Value,
+    pub headers: HashMap<String, String>,
+    pub stored_at: Instant,
+}
+
+/// A deferred response served by `GET /_jobs/{id}` once `ready_at` has passed.
+#[derive(Debug, Clone)]
+pub struct AsyncJob {
+    pub ready_at: Instant,
+    pub body: Value,
+}
+
+/// A request captured for later assertions via `GET /_history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Option<Value>,
+}
+
+/// A call count registered via `POST /_expect` and checked against the history by
+/// `GET /_verify`. Without any bound, at least one call is expected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Expectation {
+    pub method: String,
+    /// A concrete request path (`/orders/42`) or a route pattern (`/orders/{id}`)
+    pub path: String,
+    /// Exact number of calls
+    pub times: Option<usize>,
+    pub at_least: Option<usize>,
+    pub at_most: Option<usize>,
+}
+
+impl Expectation {
+    pub fn holds(&self, count: usize) -> bool {
+        if self.times.is_none() && self.at_least.is_none() && self.at_most.is_none() {
+            return count > 0;
+        }
+        self.times.is_none_or(|times| count == times)
+            && self.at_least.is_none_or(|min| count >= min)
+            && self.at_most.is_none_or(|max| count <= max)
+    }
+}
+
+/// Body of `POST /_preview`: a request to answer without touching the server's state.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreviewRequest {
+    /// Request path, optionally with a query string (e.g., "/orders?page=2")
+    pub route_path: String,
+    /// HTTP method (default: GET)
+    pub method: Option<String>,
+    pub body: Option<Value>,
+    pub headers: Option<HashMap<String, String>>,
+}
+
+/// Body of `POST /_replay`: a full request, or a position in a route's history.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ReplayRequest {
+    /// The `index`-th recorded request (oldest first) for the route pattern `path`
+    Recorded {
+        path: String,
+        index: usize,
+    },
+    Request(RecordedRequest),
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub config: Config,
+    pub options: ServerOptions,
+    /// Live route table; starts as the configured routes and grows via `/admin/routes`.
+    /// Routes are shared so matching a request never copies its templates.
+    pub routes: Arc<RwLock<RouteTable>>,
     pub storage: Arc<RwLock<HashMap<String, Value>>>,
     pub objects: Arc<RwLock<HashMap<String, Vec<StoredObject>>>>,
     pub lua_state: Arc<RwLock<HashMap<String, Value>>>,
+    /// Most recent requests per route pattern, oldest first
+    pub history: Arc<RwLock<HashMap<String, VecDeque<RecordedRequest>>>>,
+    /// Call counts registered via `POST /_expect`, in registration order
+    pub expectations: Arc<RwLock<Vec<Expectation>>>,
+    /// First responses to keyed POSTs, by request path and `Idempotency-Key`
+    pub idempotency: Arc<RwLock<HashMap<(String, String), IdempotentResponse>>>,
+    /// Responses of `cache_ttl_ms` routes, by method, path, query and body hash
+    pub response_cache: Arc<RwLock<HashMap<String, CachedResponse>>>,
+    /// Attempts seen by `flaky` routes, by request path and `Idempotency-Key`
+    pub key_attempts: Arc<RwLock<HashMap<(String, String), u32>>>,
+    /// Requests handled across all routes since startup or the last `/state/clear`
+    pub request_count: Arc<AtomicU64>,
+    /// Routes (keyed by method and pattern) that have served their cold start
+    pub warmed_routes: Arc<RwLock<HashSet<String>>>,
+    /// Requests seen per route (keyed by method and pattern) for `pattern_status` cycles
+    pub route_counters: Arc<RwLock<HashMap<String, u64>>>,
+    /// Requests matched per route (keyed by method and pattern) for `GET /_stats`
+    pub route_hits: Arc<RwLock<HashMap<String, u64>>>,
+    /// Jobs started by `async_job` routes, by job id
+    pub jobs: Arc<RwLock<HashMap<String, AsyncJob>>>,
+    /// Last `_seq` handed to a stored object when `sequence_objects` is on
+    pub object_seq: Arc<AtomicU64>,
+    /// Last id handed out per object type by the `sequence` id strategy
+    pub id_sequences: Arc<RwLock<HashMap<String, u64>>>,
+    /// Values handed out so far by sequence variables, keyed by route and variable name
+    pub variable_counters: Arc<RwLock<HashMap<String, u64>>>,
+    /// Set once startup has finished; reported by `GET /readyz`
+    pub ready: Arc<AtomicBool>,
+    /// Source of the orders `shuffle_objects` puts objects in
+    pub shuffle_rng: Arc<Mutex<StdRng>>,
+}
+
+impl AppState {
+    /// Creates server state for `config`, with the object store holding any seeded objects.
+    pub fn new(config: Config) -> Self {
+        Self::with_options(config, ServerOptions::default())
+    }
+
+    pub fn with_options(config: Config, options: ServerOptions) -> Self {
+        let object_seq = Arc::new(AtomicU64::new(0));
+        let mut objects: HashMap<String, Vec<StoredObject>> = HashMap::new();
+        for seed in config.seed.iter().flatten() {
+            let mut data = seed.data.clone();
+            if config.sequence_objects.unwrap_or(false) {
+                tag_sequence(&object_seq, &mut data);
+            }
+            objects
+                .entry(seed.object_type.clone())
+                .or_default()
+                .push(StoredObject {
+                    id: seed.id.clone(),
+                    data,
+                });
+        }
+
+        AppState {
+            routes: Arc::new(RwLock::new(RouteTable::new(
+                config.routes.iter().cloned().map(Arc::new).collect(),
+            ))),
+            config,
+            storage: Arc::new(RwLock::new(HashMap::new())),
+            objects: Arc::new(RwLock::new(objects)),
+            lua_state: Arc::new(RwLock::new(HashMap::new())),
+            history: Arc::new(RwLock::new(HashMap::new())),
+            expectations: Arc::new(RwLock::new(Vec::new())),
+            idempotency: Arc::new(RwLock::new(HashMap::new())),
+            response_cache: Arc::new(RwLock::new(HashMap::new())),
+            key_attempts: Arc::new(RwLock::new(HashMap::new())),
+            request_count: Arc::new(AtomicU64::new(0)),
+            warmed_routes: Arc::new(RwLock::new(HashSet::new())),
+            route_counters: Arc::new(RwLock::new(HashMap::new())),
+            route_hits: Arc::new(RwLock::new(HashMap::new())),
+            jobs: Arc::new(RwLock::new(HashMap::new())),
+            object_seq,
+            id_sequences: Arc::new(RwLock::new(HashMap::new())),
+            variable_counters: Arc::new(RwLock::new(HashMap::new())),
+            ready: Arc::new(AtomicBool::new(false)),
+            shuffle_rng: Arc::new(Mutex::new(shuffle_rng(options.seed))),
+            options,
+        }
+    }
+
+    /// Marks startup as finished so `GET /readyz` starts reporting ready.
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// Stamps `data` with the next `_seq` when `sequence_objects` is on.
+    pub fn sequence_object(&self, data: &mut Value) {
+        if self.config.sequence_objects.unwrap_or(false) {
+            tag_sequence(&self.object_seq, data);
+        }
+    }
+
+    /// Copies the state so requests handled against the copy leave this one untouched,
+    /// for previews. Routes are still shared.
+    pub fn detached(&self) -> AppState {
+        fn copy<T: Clone>(shared: &Arc<RwLock<T>>) -> Arc<RwLock<T>> {
+            Arc::new(RwLock::new(shared.read().unwrap().clone()))
+        }
+        let copy_counter =
+            |counter: &AtomicU64| Arc::new(AtomicU64::new(counter.load(Ordering::SeqCst)));
+
+        AppState {
+            config: self.config.clone(),
+            options: self.options.clone(),
+            routes: self.routes.clone(),
+            storage: copy(&self.storage),
+            objects: copy(&self.objects),
+            lua_state: copy(&self.lua_state),
+            history: copy(&self.history),
+            expectations: copy(&self.expectations),
+            idempotency: copy(&self.idempotency),
+            response_cache: copy(&self.response_cache),
+            key_attempts: copy(&self.key_attempts),
+            request_count: copy_counter(&self.request_count),
+            warmed_routes: copy(&self.warmed_routes),
+            route_counters: copy(&self.route_counters),
+            route_hits: copy(&self.route_hits),
+            jobs: copy(&self.jobs),
+            object_seq: copy_counter(&self.object_seq),
+            id_sequences: copy(&self.id_sequences),
+            variable_counters: copy(&self.variable_counters),
+            ready: self.ready.clone(),
+            shuffle_rng: Arc::new(Mutex::new(self.shuffle_rng.lock().unwrap().clone())),
+        }
+    }
+
+    /// Resets the per-route bookkeeping of stateful features (history, expectations,
+    /// idempotency keys, cached responses, warm routes, request counts and stats, status
+    /// patterns, async jobs, object sequence numbers, sequential ids and sequence variables)
+    /// to a fresh start.
+    pub fn reset_counters(&self) {
+        self.history.write().unwrap().clear();
+        self.expectations.write().unwrap().clear();
+        self.idempotency.write().unwrap().clear();
+        self.response_cache.write().unwrap().clear();
+        self.key_attempts.write().unwrap().clear();
+        self.warmed_routes.write().unwrap().clear();
+        self.request_count.store(0, Ordering::SeqCst);
+        self.route_counters.write().unwrap().clear();
+        self.route_hits.write().unwrap().clear();
+        self.jobs.write().unwrap().clear();
+        self.object_seq.store(0, Ordering::SeqCst);
+        self.id_sequences.write().unwrap().clear();
+        self.variable_counters.write().unwrap().clear();
+        *self.shuffle_rng.lock().unwrap() = shuffle_rng(self.options.seed);
+    }
+
+    /// A copy of the object store with each type's objects shuffled, for `shuffle_objects`.
+    pub fn shuffled_objects(&self) -> HashMap<String, Vec<StoredObject>> {
+        let mut objects = self.objects.read().unwrap().clone();
+        let mut rng = self.shuffle_rng.lock().unwrap();
+        // Types are shuffled in name order so a seed gives the same result every run
+        let mut lists: Vec<(&String, &mut Vec<StoredObject>)> = objects.iter_mut().collect();
+        lists.sort_by_key(|(name, _)| *name);
+        for (_, list) in lists {
+            list.shuffle(&mut *rng);
+        }
+        objects
+    }
+}
+
+fn shuffle_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+fn tag_sequence(counter: &AtomicU64, data: &mut Value) {
+    if let Some(object) = data.as_object_mut() {
+        let seq = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        object.insert("_seq".to_string(), Value::from(seq));
+    }
 }
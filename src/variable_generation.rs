@@ -1,73 +1,183 @@
 use crate::types::VariableConfig;
+use chrono::{DateTime, FixedOffset, NaiveDate, SecondsFormat, TimeDelta, Utc};
 use serde_json::{Value, json};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use tracing::warn;
 use uuid::Uuid;
 
-pub fn validate_variable_parameters(var_config: &VariableConfig) {
+/// Width of the range used when an integer sets `min` without `max`.
+const DEFAULT_INTEGER_SPAN: i64 = 100;
+
+/// Logs a variable generation warning and keeps it so debug mode can surface it.
+fn push_warning(warnings: &mut Vec<String>, message: String) {
+    warn!("{message}");
+    warnings.push(message);
+}
+
+pub fn validate_variable_parameters(var_config: &VariableConfig, warnings: &mut Vec<String>) {
     let var_type = var_config.var_type.as_str();
 
     match var_type {
         "uuid" => {
             if var_config.prefix.is_some() {
-                println!(
-                    "Warning: UUID type doesn't support 'prefix' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "UUID type doesn't support 'prefix' parameter. Ignoring this parameter."
+                        .to_string(),
                 );
             }
             if var_config.min.is_some() {
-                println!(
-                    "Warning: UUID type doesn't support 'min' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "UUID type doesn't support 'min' parameter. Ignoring this parameter."
+                        .to_string(),
                 );
             }
             if var_config.max.is_some() {
-                println!(
-                    "Warning: UUID type doesn't support 'max' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "UUID type doesn't support 'max' parameter. Ignoring this parameter."
+                        .to_string(),
                 );
             }
         }
         "integer" => {
             if var_config.prefix.is_some() {
-                println!(
-                    "Warning: Integer type doesn't support 'prefix' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "Integer type doesn't support 'prefix' parameter. Ignoring this parameter."
+                        .to_string(),
+                );
+            }
+        }
+        "float" => {
+            if var_config.prefix.is_some() {
+                push_warning(
+                    warnings,
+                    "Float type doesn't support 'prefix' parameter. Ignoring this parameter."
+                        .to_string(),
+                );
+            }
+        }
+        "boolean" => {
+            if var_config.prefix.is_some() || var_config.min.is_some() || var_config.max.is_some() {
+                push_warning(
+                    warnings,
+                    "Boolean type doesn't support 'prefix', 'min' or 'max' parameters. \
+                     Ignoring them."
+                        .to_string(),
+                );
+            }
+        }
+        "date" => {
+            if var_config.prefix.is_some() {
+                push_warning(
+                    warnings,
+                    "Date type doesn't support 'prefix' parameter. Ignoring this parameter."
+                        .to_string(),
                 );
             }
         }
         "string" => {
             if var_config.min.is_some() {
-                println!(
-                    "Warning: String type doesn't support 'min' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "String type doesn't support 'min' parameter. Ignoring this parameter."
+                        .to_string(),
                 );
             }
             if var_config.max.is_some() {
-                println!(
-                    "Warning: String type doesn't support 'max' parameter. Ignoring this parameter."
+                push_warning(
+                    warnings,
+                    "String type doesn't support 'max' parameter. Ignoring this parameter."
+                        .to_string(),
+                );
+            }
+        }
+        "timestamp" => {
+            if var_config.prefix.is_some() || var_config.min.is_some() || var_config.max.is_some() {
+                push_warning(
+                    warnings,
+                    "Timestamp type doesn't support 'prefix', 'min' or 'max' parameters. \
+                     Ignoring them."
+                        .to_string(),
+                );
+            }
+        }
+        "sequence" => {
+            if var_config.prefix.is_some() || var_config.min.is_some() || var_config.max.is_some() {
+                push_warning(
+                    warnings,
+                    "Sequence type doesn't support 'prefix', 'min' or 'max' parameters. \
+                     Ignoring them."
+                        .to_string(),
+                );
+            }
+        }
+        "sequence_time" => {
+            if var_config.prefix.is_some() || var_config.min.is_some() || var_config.max.is_some() {
+                push_warning(
+                    warnings,
+                    "Sequence time type doesn't support 'prefix', 'min' or 'max' parameters. \
+                     Ignoring them."
+                        .to_string(),
+                );
+            }
+        }
+        "payload_ref" => {
+            if var_config.field.is_none() {
+                push_warning(
+                    warnings,
+                    "payload_ref type requires a 'field' parameter. Using its default.".to_string(),
                 );
             }
         }
         _ => {
             // Unknown type, warn about any parameters
             if var_config.prefix.is_some() || var_config.min.is_some() || var_config.max.is_some() {
-                println!(
-                    "Warning: Unknown variable type '{var_type}'. Parameters may not be supported."
+                push_warning(
+                    warnings,
+                    format!("Unknown variable type '{var_type}'. Parameters may not be supported."),
                 );
             }
         }
     }
 }
 
-pub fn generate_variable_value(var_config: &VariableConfig) -> Value {
-    validate_variable_parameters(var_config);
+pub fn generate_variable_value(
+    var_config: &VariableConfig,
+    payload: Option<&Value>,
+    warnings: &mut Vec<String>,
+) -> Value {
+    validate_variable_parameters(var_config, warnings);
 
     match var_config.var_type.as_str() {
         "uuid" => {
             json!(Uuid::new_v4().to_string())
         }
         "integer" => {
-            let min = var_config.min.unwrap_or(0);
-            let max = var_config.max.unwrap_or(i64::MAX);
+            let min = integer_bound(var_config.min.as_ref(), "min", 0, warnings);
+            let max = match (&var_config.min, &var_config.max) {
+                (Some(_), None) => {
+                    let max = min.saturating_add(DEFAULT_INTEGER_SPAN);
+                    push_warning(
+                        warnings,
+                        format!(
+                            "Integer has 'min' but no 'max'. Using min + {DEFAULT_INTEGER_SPAN} ({max})."
+                        ),
+                    );
+                    max
+                }
+                _ => integer_bound(var_config.max.as_ref(), "max", i64::MAX, warnings),
+            };
 
             if min > max {
-                println!(
-                    "Warning: min value ({min}) is greater than max value ({max}). Using default range."
+                push_warning(
+                    warnings,
+                    format!(
+                        "min value ({min}) is greater than max value ({max}). Using default range."
+                    ),
                 );
                 json!(rand::random::<u32>())
             } else {
@@ -80,6 +190,13 @@ pub fn generate_variable_value(var_config: &VariableConfig) -> Value {
                 }
             }
         }
+        "float" => generate_float(var_config, warnings),
+        "boolean" => {
+            let probability = var_config.true_probability.unwrap_or(0.5);
+            json!(rand::random::<f64>() < probability)
+        }
+        "date" => generate_date(var_config, warnings),
+        "timestamp" => generate_timestamp(var_config, warnings),
         "string" => {
             let base_string = format!("generated_{}", rand::random::<u16>());
             if let Some(prefix) = &var_config.prefix {
@@ -88,10 +205,336 @@ pub fn generate_variable_value(var_config: &VariableConfig) -> Value {
                 json!(base_string)
             }
         }
+        "payload_ref" => {
+            let copied = var_config
+                .field
+                .as_ref()
+                .and_then(|field| payload?.get(field));
+            copied
+                .or(var_config.default.as_ref())
+                .cloned()
+                .unwrap_or(Value::Null)
+        }
         _ => var_config.default.clone().unwrap_or(json!("default")),
     }
 }
 
+fn integer_bound(
+    bound: Option<&Value>,
+    name: &str,
+    fallback: i64,
+    warnings: &mut Vec<String>,
+) -> i64 {
+    match bound {
+        None => fallback,
+        Some(value) => value.as_i64().unwrap_or_else(|| {
+            push_warning(
+                warnings,
+                format!("Integer '{name}' must be a whole number, got {value}. Ignoring it."),
+            );
+            fallback
+        }),
+    }
+}
+
+fn float_bound(
+    bound: Option<&Value>,
+    name: &str,
+    fallback: f64,
+    warnings: &mut Vec<String>,
+) -> f64 {
+    match bound {
+        None => fallback,
+        Some(value) => value.as_f64().unwrap_or_else(|| {
+            push_warning(
+                warnings,
+                format!("Float '{name}' must be a number, got {value}. Ignoring it."),
+            );
+            fallback
+        }),
+    }
+}
+
+fn generate_float(var_config: &VariableConfig, warnings: &mut Vec<String>) -> Value {
+    let mut min = float_bound(var_config.min.as_ref(), "min", 0.0, warnings);
+    let mut max = float_bound(var_config.max.as_ref(), "max", 1.0, warnings);
+
+    if min > max {
+        push_warning(
+            warnings,
+            format!("min value ({min}) is greater than max value ({max}). Using default range."),
+        );
+        (min, max) = (0.0, 1.0);
+    }
+
+    let mut value = min + rand::random::<f64>() * (max - min);
+    if let Some(precision) = var_config.precision {
+        let scale = 10f64.powi(precision as i32);
+        // Rounding can step just past a bound that has more decimals than the precision
+        value = ((value * scale).round() / scale).clamp(min, max);
+    }
+    json!(value)
+}
+
+fn date_bound(
+    bound: Option<&Value>,
+    name: &str,
+    fallback: NaiveDate,
+    warnings: &mut Vec<String>,
+) -> NaiveDate {
+    let Some(value) = bound else {
+        return fallback;
+    };
+    match value.as_str().map(|s| s.parse::<NaiveDate>()) {
+        Some(Ok(date)) => date,
+        _ => {
+            push_warning(
+                warnings,
+                format!(
+                    "Date '{name}' must be an ISO date (YYYY-MM-DD), got {value}. Ignoring it."
+                ),
+            );
+            fallback
+        }
+    }
+}
+
+fn generate_date(var_config: &VariableConfig, warnings: &mut Vec<String>) -> Value {
+    let default_min = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let default_max = NaiveDate::from_ymd_opt(2099, 12, 31).unwrap();
+    let mut min = date_bound(var_config.min.as_ref(), "min", default_min, warnings);
+    let mut max = date_bound(var_config.max.as_ref(), "max", default_max, warnings);
+
+    if min > max {
+        push_warning(
+            warnings,
+            format!("min date ({min}) is after max date ({max}). Using default range."),
+        );
+        (min, max) = (default_min, default_max);
+    }
+
+    let span = (max - min).num_days() as u64 + 1;
+    let date = min + chrono::Days::new(rand::random::<u64>() % span);
+
+    let format = var_config.format.as_deref().unwrap_or("%Y-%m-%d");
+    // Unknown specifiers surface as a fmt error rather than a panic when written
+    let mut formatted = String::new();
+    if write!(formatted, "{}", date.format(format)).is_err() {
+        push_warning(
+            warnings,
+            format!("Invalid date format '{format}'. Using %Y-%m-%d."),
+        );
+        formatted = date.to_string();
+    }
+    json!(formatted)
+}
+
+/// Generates the `position`-th (from 0) value of a `sequence` variable: `start` (default: 1)
+/// plus `position` times `step` (default: 1).
+pub fn generate_sequence(
+    var_config: &VariableConfig,
+    position: u64,
+    warnings: &mut Vec<String>,
+) -> Value {
+    validate_variable_parameters(var_config, warnings);
+
+    let start = integer_bound(var_config.start.as_ref(), "start", 1, warnings);
+    let offset = var_config.step.unwrap_or(1).saturating_mul(position as i64);
+    json!(start.saturating_add(offset))
+}
+
+/// Start of a `sequence_time` without a `start`.
+const DEFAULT_SEQUENCE_START: &str = "2024-01-01T00:00:00Z";
+
+/// Generates the `position`-th (from 0) timestamp of a `sequence_time` variable: `start`
+/// plus `position` times `step` seconds (default: 1), as RFC 3339 or in `format`.
+pub fn generate_sequence_time(
+    var_config: &VariableConfig,
+    position: u64,
+    warnings: &mut Vec<String>,
+) -> Value {
+    validate_variable_parameters(var_config, warnings);
+
+    let default_start = DateTime::parse_from_rfc3339(DEFAULT_SEQUENCE_START).unwrap();
+    let start = match &var_config.start {
+        None => default_start,
+        Some(value) => match value.as_str().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(start)) => start,
+            _ => {
+                push_warning(
+                    warnings,
+                    format!(
+                        "Sequence time 'start' must be an RFC 3339 timestamp, got {value}. \
+                         Using {DEFAULT_SEQUENCE_START}."
+                    ),
+                );
+                default_start
+            }
+        },
+    };
+    let offset = var_config.step.unwrap_or(1).saturating_mul(position as i64);
+    let time = TimeDelta::try_seconds(offset)
+        .and_then(|delta| start.checked_add_signed(delta))
+        .unwrap_or_else(|| {
+            push_warning(
+                warnings,
+                format!("Sequence time offset ({offset} seconds) is out of range. Using 'start'."),
+            );
+            start
+        });
+    format_timestamp(time, var_config.format.as_deref(), warnings)
+}
+
+/// Generates the current UTC time, shifted by `offset_seconds`, as RFC 3339 or in `format`.
+fn generate_timestamp(var_config: &VariableConfig, warnings: &mut Vec<String>) -> Value {
+    let now = Utc::now();
+    let offset = var_config.offset_seconds.unwrap_or(0);
+    let time = TimeDelta::try_seconds(offset)
+        .and_then(|delta| now.checked_add_signed(delta))
+        .unwrap_or_else(|| {
+            push_warning(
+                warnings,
+                format!("Timestamp 'offset_seconds' ({offset}) is out of range. Ignoring it."),
+            );
+            now
+        });
+    format_timestamp(time.fixed_offset(), var_config.format.as_deref(), warnings)
+}
+
+/// Formats a generated timestamp as RFC 3339 with second precision, or in a strftime-style
+/// `format`, falling back to RFC 3339 when the format is invalid.
+fn format_timestamp(
+    time: DateTime<FixedOffset>,
+    format: Option<&str>,
+    warnings: &mut Vec<String>,
+) -> Value {
+    let Some(format) = format else {
+        return json!(time.to_rfc3339_opts(SecondsFormat::Secs, true));
+    };
+    let mut formatted = String::new();
+    if write!(formatted, "{}", time.format(format)).is_err() {
+        push_warning(
+            warnings,
+            format!("Invalid timestamp format '{format}'. Using RFC 3339."),
+        );
+        formatted = time.to_rfc3339_opts(SecondsFormat::Secs, true);
+    }
+    json!(formatted)
+}
+
+/// Transformations a derived variable can apply to its source with `expr`.
+const DERIVED_EXPRESSIONS: [&str; 4] = ["copy", "lowercase", "uppercase", "slug"];
+
+/// Orders variable names so every derived variable comes after the one it is derived
+/// `from`. Fails on unknown sources or expressions and on derivation cycles.
+pub fn variable_order(variables: &HashMap<String, VariableConfig>) -> Result<Vec<&str>, String> {
+    let mut names: Vec<&str> = variables.keys().map(String::as_str).collect();
+    names.sort();
+
+    let mut ordered = Vec::with_capacity(names.len());
+    let mut placed = HashSet::new();
+    for name in names {
+        // Each variable has at most one source, so its dependencies form a chain
+        let mut chain: Vec<&str> = Vec::new();
+        let mut current = name;
+        while !placed.contains(current) {
+            if let Some(start) = chain.iter().position(|&n| n == current) {
+                let mut cycle = chain[start..].to_vec();
+                cycle.push(current);
+                return Err(format!(
+                    "Variables form a derivation cycle: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+            chain.push(current);
+
+            let config = &variables[current];
+            if let Some(expr) = &config.expr
+                && !DERIVED_EXPRESSIONS.contains(&expr.as_str())
+            {
+                return Err(format!(
+                    "Variable '{current}' has unknown expr '{expr}' (expected one of: {})",
+                    DERIVED_EXPRESSIONS.join(", ")
+                ));
+            }
+            match &config.from {
+                None if config.expr.is_some() => {
+                    return Err(format!("Variable '{current}' has an 'expr' but no 'from'"));
+                }
+                None => break,
+                Some(source) => match variables.get_key_value(source) {
+                    Some((source, _)) => current = source,
+                    None => {
+                        return Err(format!(
+                            "Variable '{current}' is derived from unknown variable '{source}'"
+                        ));
+                    }
+                },
+            }
+        }
+
+        for name in chain.into_iter().rev() {
+            if placed.insert(name) {
+                ordered.push(name);
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// Computes a derived variable from its source's generated value.
+pub fn derive_variable_value(var_config: &VariableConfig, source: &Value) -> Value {
+    let text = match source {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+
+    match var_config.expr.as_deref().unwrap_or("copy") {
+        "lowercase" => json!(text.to_lowercase()),
+        "uppercase" => json!(text.to_uppercase()),
+        "slug" => {
+            let slug = text
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("-");
+            json!(slug)
+        }
+        _ => source.clone(),
+    }
+}
+
+/// Decides whether a variable appears in this response, per its `present_probability`.
+pub fn is_variable_present(var_config: &VariableConfig) -> bool {
+    var_config
+        .present_probability
+        .is_none_or(|probability| rand::random::<f64>() < probability)
+}
+
+/// Removes object entries whose value is exactly the placeholder of an omitted variable.
+pub fn omit_variables_in_value(value: &Value, omitted: &HashSet<String>) -> Value {
+    match value {
+        Value::Object(obj) => Value::Object(
+            obj.iter()
+                .filter(|(_, v)| {
+                    !v.as_str()
+                        .and_then(|s| s.strip_prefix('{')?.strip_suffix('}'))
+                        .is_some_and(|name| omitted.contains(name))
+                })
+                .map(|(k, v)| (k.clone(), omit_variables_in_value(v, omitted)))
+                .collect(),
+        ),
+        Value::Array(arr) => Value::Array(
+            arr.iter()
+                .map(|v| omit_variables_in_value(v, omitted))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
 pub fn replace_variables_in_value(value: &Value, variables: &HashMap<String, Value>) -> Value {
     crate::interpolation::replace_simple_placeholders(value, |placeholder| {
         variables.get(placeholder).cloned()
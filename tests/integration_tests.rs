@@ -1,6 +1,7 @@
 use reqwest::Client;
 use serde_json::{Value, json};
-use std::process::{Child, Command};
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -16,6 +17,19 @@ impl TestServer {
     }
 
     async fn start_with_config(config_file: &str) -> Self {
+        Self::start_with_args(config_file, &[]).await
+    }
+
+    async fn start_with_args(config_file: &str, extra_args: &[&str]) -> Self {
+        Self::launch(config_file, extra_args, false).await
+    }
+
+    /// Starts a server whose stdout is piped so it can be inspected after `stop`.
+    async fn start_capturing_stdout(config_file: &str, extra_args: &[&str]) -> Self {
+        Self::launch(config_file, extra_args, true).await
+    }
+
+    async fn launch(config_file: &str, extra_args: &[&str], capture_stdout: bool) -> Self {
         // Find an available port starting from 3010
         let port = 3010;
 
@@ -32,6 +46,12 @@ impl TestServer {
                     "--port",
                     &test_port.to_string(),
                 ])
+                .args(extra_args)
+                .stdout(if capture_stdout {
+                    Stdio::piped()
+                } else {
+                    Stdio::inherit()
+                })
                 .spawn()
                 .expect("Failed to start server");
 
@@ -43,11 +63,11 @@ impl TestServer {
 
             for _ in 0..50 {
                 // Increased wait time
-                if let Ok(response) = client.get(format!("{}/health", base_url)).send().await {
-                    if response.status().is_success() {
-                        server_started = true;
-                        break;
-                    }
+                if let Ok(response) = client.get(format!("{}/health", base_url)).send().await
+                    && response.status().is_success()
+                {
+                    server_started = true;
+                    break;
                 }
                 sleep(Duration::from_millis(200)).await;
             }
@@ -60,6 +80,7 @@ impl TestServer {
             } else {
                 // Kill the process and try next port
                 let _ = child.kill();
+                let _ = child.wait();
             }
         }
 
@@ -113,6 +134,20 @@ impl TestServer {
     }
 }
 
+impl TestServer {
+    /// Stops the server and returns everything it wrote to a piped stdout.
+    fn stop(mut self) -> String {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+
+        let mut output = String::new();
+        if let Some(stdout) = self.process.stdout.as_mut() {
+            let _ = stdout.read_to_string(&mut output);
+        }
+        output
+    }
+}
+
 impl Drop for TestServer {
     fn drop(&mut self) {
         let _ = self.process.kill();
@@ -556,10 +591,116 @@ async fn test_integer_variable_with_min_max() {
     let price2 = response2["price"].as_i64().unwrap();
 
     // All values should still be in range
-    assert!(quantity1 >= 1 && quantity1 <= 100);
-    assert!(quantity2 >= 1 && quantity2 <= 100);
-    assert!(price1 >= 500 && price1 <= 2000);
-    assert!(price2 >= 500 && price2 <= 2000);
+    assert!((1..=100).contains(&quantity1));
+    assert!((1..=100).contains(&quantity2));
+    assert!((500..=2000).contains(&price1));
+    assert!((500..=2000).contains(&price2));
+}
+
+#[tokio::test]
+async fn test_integer_variable_default_max() {
+    let server = TestServer::start_with_args("config.yaml", &["--debug"]).await;
+
+    for _ in 0..10 {
+        let response = server
+            .post_json("/test/variables/integer-min", json!({}))
+            .await
+            .expect("Failed to test integer variables");
+
+        // Only min is set, so the range defaults to [min, min + 100]
+        let restock = response["restock"].as_i64().unwrap();
+        assert!(
+            (5..=105).contains(&restock),
+            "Restock should default to a small range, got {}",
+            restock
+        );
+        assert_eq!(
+            response["_warnings"],
+            json!(["Integer has 'min' but no 'max'. Using min + 100 (105)."])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_float_variable() {
+    let server = TestServer::start_with_args("config.yaml", &["--debug"]).await;
+
+    for _ in 0..10 {
+        let response = server
+            .post_json("/test/variables/float", json!({}))
+            .await
+            .expect("Failed to test float variables");
+
+        let rating = response["rating"].as_f64().unwrap();
+        assert!(
+            (0.0..=5.0).contains(&rating),
+            "Rating out of range: {rating}"
+        );
+        // At most two decimal places survive the rounding
+        let text = response["rating"].to_string();
+        let decimals = text.split_once('.').map_or(0, |(_, digits)| digits.len());
+        assert!(decimals <= 2, "Rating should be rounded, got {text}");
+
+        let weight = response["weight"].as_f64().unwrap();
+        assert!((0.0..=1.0).contains(&weight));
+        assert_eq!(
+            response["_warnings"],
+            json!(["Float type doesn't support 'prefix' parameter. Ignoring this parameter."])
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_boolean_variable_probability() {
+    let server = TestServer::start().await;
+    let client = Client::new();
+
+    let mut trues = 0;
+    for _ in 0..100 {
+        let response: Value = client
+            .post(format!("{}/test/variables/boolean", server.base_url))
+            .json(&json!({}))
+            .send()
+            .await
+            .expect("Failed to test boolean variables")
+            .json()
+            .await
+            .expect("Failed to parse response");
+        if response["enabled"]
+            .as_bool()
+            .expect("enabled should be a boolean")
+        {
+            trues += 1;
+        }
+    }
+
+    // true_probability is 0.9, so fewer than 75 trues is a five-sigma outlier
+    assert!(trues >= 75, "Expected about 90 trues in 100, got {trues}");
+}
+
+#[tokio::test]
+async fn test_optional_variable_omission() {
+    let server = TestServer::start().await;
+
+    let mut present = 0;
+    for _ in 0..100 {
+        let response = server
+            .post_json("/test/variables/optional", json!({}))
+            .await
+            .expect("Failed to test optional variables");
+
+        assert_eq!(response["message"], "Optional variable test");
+        // The key is either absent or generated, never null or an unreplaced placeholder
+        if let Some(nickname) = response.get("nickname") {
+            assert!(nickname.as_str().unwrap().starts_with("nick_generated_"));
+            present += 1;
+        }
+    }
+
+    assert!(
+        (25..=75).contains(&present),
+        "About half the responses should include the field, got {present}/100"
+    );
 }
 
 #[tokio::test]
@@ -576,6 +717,10 @@ async fn test_uuid_variable_ignores_invalid_params() {
         .expect("Failed to test UUID variables");
 
     assert_eq!(response["message"], "UUID variable test");
+    assert!(
+        response.get("_warnings").is_none(),
+        "Warnings are only attached in debug mode"
+    );
 
     // Verify id is a valid UUID format
     let id = response["id"].as_str().unwrap();
@@ -607,6 +752,34 @@ async fn test_uuid_variable_ignores_invalid_params() {
     assert!(id2.contains('-'), "Second UUID should also contain hyphens");
 }
 
+#[tokio::test]
+async fn test_date_variable_generation() {
+    use chrono::NaiveDate;
+
+    let server = TestServer::start().await;
+
+    let min = NaiveDate::from_ymd_opt(1980, 1, 1).unwrap();
+    let max = NaiveDate::from_ymd_opt(2000, 12, 31).unwrap();
+
+    for _ in 0..10 {
+        let response = server
+            .post_json("/test/variables/date", json!({}))
+            .await
+            .expect("Failed to test date variables");
+
+        assert_eq!(response["message"], "Date variable test");
+
+        let dob = NaiveDate::parse_from_str(response["dob"].as_str().unwrap(), "%Y-%m-%d")
+            .expect("dob should be an ISO date");
+        assert!(dob >= min && dob <= max, "dob {dob} out of range");
+
+        // Custom formats are applied to the generated date
+        let expires = NaiveDate::parse_from_str(response["expires"].as_str().unwrap(), "%d/%m/%Y")
+            .expect("expires should use the configured format");
+        assert_eq!(expires.format("%Y-%m").to_string(), "2030-06");
+    }
+}
+
 #[tokio::test]
 async fn test_payload_interpolation() {
     let server = TestServer::start().await;
@@ -644,6 +817,30 @@ async fn test_payload_interpolation() {
     assert_eq!(minimal_order["status"], "pending"); // hardcoded in template
 }
 
+#[tokio::test]
+async fn test_optional_trailing_segment() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let all = server
+        .get_json("/catalog")
+        .await
+        .expect("Failed to list catalog");
+    assert_eq!(all, json!({"items": ["A1", "B2"]}));
+
+    let one = server
+        .get_json("/catalog/A1")
+        .await
+        .expect("Failed to get catalog item");
+    assert_eq!(one, json!({"sku": "A1", "name": "Item A1"}));
+
+    let response = Client::new()
+        .get(format!("{}/catalog/A1/extra", server.base_url))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 404);
+}
+
 #[tokio::test]
 async fn test_lua_basic_functionality() {
     let server = TestServer::start_with_config("lua-test.yaml").await;
@@ -749,6 +946,215 @@ async fn test_lua_state_management() {
     assert_eq!(body4["request_number"], 4);
 }
 
+#[tokio::test]
+async fn test_pattern_status_cycle() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    // Same success, success, fail cycle as the Lua flaky endpoint
+    let expected = [200, 200, 503, 200, 200, 503, 200];
+    for (i, expected_status) in expected.iter().enumerate() {
+        let response = server
+            .get_with_headers("/flaky-pattern", vec![])
+            .await
+            .expect("Failed to get flaky-pattern");
+        assert_eq!(response.status(), *expected_status, "request {}", i + 1);
+
+        let body: Value = response.json().await.expect("Failed to parse JSON");
+        if *expected_status == 200 {
+            assert_eq!(body["message"], "Request successful");
+        } else {
+            assert_eq!(body["error"], "Simulated failure");
+        }
+    }
+
+    // Clearing state mid-cycle restarts it from the first step
+    server.clear_state().await.expect("Failed to clear state");
+    for (i, expected_status) in [200, 200, 503].iter().enumerate() {
+        let response = server
+            .get_with_headers("/flaky-pattern", vec![])
+            .await
+            .expect("Failed to get flaky-pattern");
+        assert_eq!(
+            response.status(),
+            *expected_status,
+            "request {} after clear",
+            i + 1
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_lua_scripts_endpoint() {
+    let server = TestServer::start_with_args("lua-test.yaml", &["--inspect-lua"]).await;
+
+    let listing = server
+        .get_json("/_lua")
+        .await
+        .expect("Failed to list scripts");
+    let scripts = listing["scripts"].as_array().unwrap();
+    let flaky = scripts
+        .iter()
+        .find(|script| script["path"] == "/flaky-endpoint")
+        .expect("The flaky endpoint's script should be listed");
+    assert_eq!(flaky["method"], "GET");
+    let source = flaky["source"].as_str().unwrap();
+    assert!(source.contains("if count % 3 == 0 then"));
+
+    // Template-only routes have no script to list
+    assert!(
+        !scripts
+            .iter()
+            .any(|script| script["path"] == "/flaky-pattern")
+    );
+    drop(server);
+
+    let server =
+        TestServer::start_with_args("lua-test.yaml", &["--inspect-lua", "--no-source"]).await;
+    let listing = server
+        .get_json("/_lua")
+        .await
+        .expect("Failed to list scripts");
+    let scripts = listing["scripts"].as_array().unwrap();
+    assert!(!scripts.is_empty());
+    assert!(scripts.iter().all(|script| script.get("source").is_none()));
+}
+
+#[tokio::test]
+async fn test_lua_log_reaches_server_logs() {
+    let server = TestServer::start_capturing_stdout("lua-test.yaml", &[]).await;
+
+    let response = server
+        .get_with_headers("/auth-check", vec![("user", "mallory")])
+        .await
+        .expect("Failed to get auth-check");
+    assert_eq!(response.status(), 401);
+
+    let output = server.stop();
+    let line = |text: &str| {
+        output
+            .lines()
+            .find(|line| line.contains(text))
+            .unwrap_or_else(|| panic!("no log line with '{text}' in: {output}"))
+            .to_string()
+    };
+    assert!(line("[lua /auth-check] auth attempt").contains("INFO"));
+    assert!(line("[lua /auth-check] auth rejected for mallory").contains("WARN"));
+}
+
+#[tokio::test]
+async fn test_status_weights_distribution() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let client = Client::new();
+    let total = 400;
+    let mut failures = 0;
+    for _ in 0..total {
+        let response = client
+            .get(format!("{}/weighted-status", server.base_url))
+            .send()
+            .await
+            .expect("Failed to get weighted-status");
+        let status = response.status();
+        let body: Value = response.json().await.expect("Failed to parse JSON");
+        match status.as_u16() {
+            200 => assert_eq!(body["message"], "Request successful"),
+            500 => {
+                assert_eq!(body["error"], "Internal error");
+                failures += 1;
+            }
+            other => panic!("unexpected status {other}"),
+        }
+    }
+
+    // 20% expected; the bounds are over six standard deviations away
+    let rate = f64::from(failures) / f64::from(total);
+    assert!((0.08..=0.32).contains(&rate), "failure rate {rate}");
+}
+
+#[tokio::test]
+async fn test_lua_response_helper() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let response = server
+        .get_with_headers("/account-status", vec![])
+        .await
+        .expect("Failed to get account status");
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-account-tier"], "pro");
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body, json!({"status": "active", "plan": "pro"}));
+}
+
+#[tokio::test]
+async fn test_repeated_query_params_in_lua() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let body = server
+        .get_json("/tagged?tag=a&tag=b&sort=name")
+        .await
+        .expect("Failed to get tagged");
+    assert_eq!(body["tags"], json!(["a", "b"]));
+    assert_eq!(body["tag_count"], 2);
+    assert_eq!(body["sort"], "name");
+
+    let body = server
+        .get_json("/tagged?tag=solo")
+        .await
+        .expect("Failed to get tagged");
+    assert_eq!(body["tags"], json!(["solo"]));
+}
+
+#[tokio::test]
+async fn test_repeated_query_params_in_templates() {
+    let server = TestServer::start().await;
+
+    let body = server
+        .get_json("/products?tag=red&tag=blue&page=2")
+        .await
+        .expect("Failed to get products");
+    assert_eq!(body["tags"], json!(["red", "blue"]));
+    assert_eq!(body["first_tag"], "red");
+    assert_eq!(body["summary"], "page 2");
+}
+
+#[tokio::test]
+async fn test_retry_after_from_state() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    for expected in ["3", "2", "1"] {
+        let response = server
+            .get_with_headers("/throttled", vec![])
+            .await
+            .expect("Failed to get throttled");
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], expected);
+    }
+
+    let response = server
+        .get_with_headers("/throttled", vec![])
+        .await
+        .expect("Failed to get throttled");
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("retry-after").is_none());
+
+    let response = server
+        .get_with_headers("/throttled-window", vec![])
+        .await
+        .expect("Failed to get throttled window");
+    assert_eq!(response.status(), 429);
+    let wait: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .expect("Retry-After should be a number of seconds");
+    assert!((29..=30).contains(&wait), "Unexpected Retry-After {wait}");
+}
+
 #[tokio::test]
 async fn test_lua_state_persistence_across_endpoints() {
     let server = TestServer::start_with_config("lua-test.yaml").await;
@@ -771,6 +1177,40 @@ async fn test_lua_state_persistence_across_endpoints() {
     assert_eq!(body["request_number"], 3);
 }
 
+#[tokio::test]
+async fn test_clear_lua_state_only() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let message = server
+        .post_json("/secret-message", json!({"message": "keep me"}))
+        .await
+        .expect("Failed to store message");
+    let id = message["id"].as_str().unwrap();
+
+    let _ = server.get_with_headers("/flaky-endpoint", vec![]).await;
+    let _ = server.get_with_headers("/flaky-endpoint", vec![]).await;
+
+    let cleared = server
+        .post_json("/state/clear/lua", json!({}))
+        .await
+        .expect("Failed to clear Lua state");
+    assert_eq!(cleared["cleared"], json!({"lua_state": 1}));
+
+    // The counter starts over while stored objects survive
+    let body = server
+        .get_json("/flaky-endpoint")
+        .await
+        .expect("Failed to get flaky-endpoint");
+    assert_eq!(body["request_number"], 1);
+
+    let debug = server
+        .get_json("/debug-objects")
+        .await
+        .expect("Failed to get objects");
+    assert_eq!(debug["messages"][0]["id"], id);
+}
+
 #[tokio::test]
 async fn test_traditional_template_still_works() {
     let server = TestServer::start_with_config("lua-test.yaml").await;
@@ -923,3 +1363,3105 @@ async fn test_lua_object_access_and_string_reversal() {
     assert_eq!(body["error"], "Message not found");
     assert_eq!(body["id"], "non-existent-id");
 }
+
+#[tokio::test]
+async fn test_lua_resolve_helper() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    let first = server
+        .post_json("/secret-message", json!({"message": "first"}))
+        .await
+        .expect("Failed to post first message");
+    server
+        .post_json("/secret-message", json!({"message": "second"}))
+        .await
+        .expect("Failed to post second message");
+
+    let id = first["id"].as_str().unwrap();
+    let response = server
+        .get_json(&format!("/resolve-message/{}", id))
+        .await
+        .expect("Failed to resolve message");
+
+    assert_eq!(response["message"], "first");
+    assert_eq!(response["all_messages"], json!(["first", "second"]));
+    assert!(response.get("missing").is_none());
+}
+
+#[tokio::test]
+async fn test_resolve_refs_in_lua_output() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for message in ["hello", "world"] {
+        server
+            .post_json("/secret-message", json!({"message": message}))
+            .await
+            .expect("Failed to post message");
+    }
+
+    let digest = server
+        .get_json("/message-digest")
+        .await
+        .expect("Failed to get digest");
+    assert_eq!(digest["messages"], json!(["hello", "world"]));
+    assert_eq!(digest["generated_by"], "lua");
+}
+
+#[tokio::test]
+async fn test_lua_defaults_access() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let response = server
+        .post_json("/lua-defaults", json!({}))
+        .await
+        .expect("Failed to post to lua-defaults");
+    assert_eq!(response["customer"], "Anonymous");
+    assert_eq!(response["role"], "user");
+    assert!(response.get("unknown").is_none());
+
+    let response = server
+        .post_json(
+            "/lua-defaults",
+            json!({"customer": "Jane", "role": "admin"}),
+        )
+        .await
+        .expect("Failed to post to lua-defaults");
+    assert_eq!(response["customer"], "Jane");
+    assert_eq!(response["role"], "admin");
+}
+
+#[tokio::test]
+async fn test_lua_raw_body_access() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    // Whitespace and key order must survive exactly as sent
+    let raw = "{ \"amount\":  10,\n  \"currency\": \"EUR\" }";
+    let signature = raw
+        .bytes()
+        .fold(0u64, |sum, b| (sum * 31 + b as u64) % 1_000_000_007);
+
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/verify-signature", server.base_url))
+        .header("content-type", "application/json")
+        .header("x-signature", signature.to_string())
+        .body(raw)
+        .send()
+        .await
+        .expect("Failed to post signed body");
+    assert_eq!(response.status(), 200);
+
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["raw_body"], raw);
+    assert_eq!(body["verified"], true);
+
+    let response = client
+        .post(format!("{}/verify-signature", server.base_url))
+        .header("x-signature", "0")
+        .body(raw)
+        .send()
+        .await
+        .expect("Failed to post badly signed body");
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn test_multipart_form_payload() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let boundary = "nugget-boundary";
+    let form = format!(
+        "--{boundary}\r\n\
+         Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+         Quarterly report\r\n\
+         --{boundary}\r\n\
+         Content-Disposition: form-data; name=\"attachment\"; filename=\"report.csv\"\r\n\
+         Content-Type: text/csv\r\n\r\n\
+         a,b\n1,2\r\n\
+         --{boundary}--\r\n"
+    );
+
+    let client = Client::new();
+    let post_form = |path: &str| {
+        client
+            .post(format!("{}{}", server.base_url, path))
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(form.clone())
+            .send()
+    };
+
+    let response = post_form("/documents").await.expect("Failed to post form");
+    assert_eq!(response.status(), 201);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["title"], "Quarterly report");
+    assert_eq!(
+        body["attachment"],
+        json!({"filename": "report.csv", "content_type": "text/csv", "size": 7})
+    );
+
+    let response = post_form("/uploads").await.expect("Failed to post form");
+    assert_eq!(response.status(), 201);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["title"], "Quarterly report");
+    assert_eq!(body["filename"], "report.csv");
+    assert_eq!(body["size"], 7);
+}
+
+#[tokio::test]
+async fn test_embedded_library_server() {
+    let config: nugget::Config = serde_json::from_value(json!({
+        "routes": [
+            {
+                "path": "/embedded",
+                "method": "GET",
+                "response": {
+                    "status": 200,
+                    "body": { "message": "served in-process" }
+                }
+            }
+        ]
+    }))
+    .expect("Failed to build config");
+
+    let app = nugget::build_router(nugget::AppState::new(config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind listener");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let response: Value = Client::new()
+        .get(format!("http://{}/embedded", addr))
+        .send()
+        .await
+        .expect("Failed to reach embedded server")
+        .json()
+        .await
+        .expect("Failed to parse response");
+
+    assert_eq!(response["message"], "served in-process");
+}
+
+#[tokio::test]
+async fn test_admin_route_registration() {
+    let server = TestServer::start_with_args("config.yaml", &["--admin-routes"]).await;
+
+    let route = json!({
+        "path": "/runtime/greeting",
+        "method": "GET",
+        "response": {
+            "status": 200,
+            "body": { "message": "registered at runtime" }
+        }
+    });
+
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/admin/routes", server.base_url))
+        .json(&route)
+        .send()
+        .await
+        .expect("Failed to register route");
+    assert_eq!(response.status(), 201);
+
+    let greeting = server
+        .get_json("/runtime/greeting")
+        .await
+        .expect("Failed to call runtime route");
+    assert_eq!(greeting["message"], "registered at runtime");
+
+    // Registering the same path and method again is a conflict
+    let duplicate = client
+        .post(format!("{}/admin/routes", server.base_url))
+        .json(&route)
+        .send()
+        .await
+        .expect("Failed to send duplicate route");
+    assert_eq!(duplicate.status(), 409);
+}
+
+#[tokio::test]
+async fn test_config_parse_error_reports_location() {
+    let config_path = std::env::temp_dir().join("nugget-tab-indented.yaml");
+    std::fs::write(
+        &config_path,
+        "routes:\n  - path: /broken\n\tmethod: GET\n    response:\n      body: {}\n",
+    )
+    .expect("Failed to write config");
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "--config", config_path.to_str().unwrap()])
+        .output()
+        .expect("Failed to run server");
+
+    assert!(!output.status.success(), "Malformed config should fail");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("at line 3, column 1"),
+        "Error should point at the tab-indented line, got: {}",
+        stderr
+    );
+    assert!(
+        stderr.contains("> 3 | \tmethod: GET"),
+        "Error should include a snippet of the offending line, got: {}",
+        stderr
+    );
+}
+
+#[tokio::test]
+async fn test_derived_variables() {
+    let server = TestServer::start().await;
+
+    let body = server
+        .post_json("/test/variables/derived", json!({}))
+        .await
+        .expect("Failed to generate derived variables");
+
+    let name = body["name"].as_str().unwrap();
+    assert!(name.starts_with("Blue Widget generated_"));
+    let expected_slug = name.to_lowercase().replace([' ', '_'], "-");
+    assert_eq!(body["slug"], expected_slug);
+    assert_eq!(body["code"], expected_slug.to_uppercase());
+}
+
+#[tokio::test]
+async fn test_payload_ref_variables() {
+    let server = TestServer::start().await;
+
+    let body = server
+        .post_json(
+            "/test/variables/payload-ref",
+            json!({"customer": {"name": "Acme"}}),
+        )
+        .await
+        .expect("Failed to copy payload fields");
+
+    assert!(body["id"].as_str().is_some());
+    assert_eq!(body["owner"], json!({"name": "Acme"}));
+    assert_eq!(body["priority"], "normal");
+
+    let body = server
+        .post_json(
+            "/test/variables/payload-ref",
+            json!({"customer": "Globex", "urgency": 3}),
+        )
+        .await
+        .expect("Failed to copy payload fields");
+    assert_eq!(body["owner"], "Globex");
+    assert_eq!(body["priority"], 3);
+}
+
+#[tokio::test]
+async fn test_derived_variable_cycle_fails_at_startup() {
+    let config_path = std::env::temp_dir().join("nugget-derived-cycle.yaml");
+    std::fs::write(
+        &config_path,
+        "routes:\n  - path: /cycle\n    method: POST\n    variables:\n      a:\n        type: string\n        from: b\n      b:\n        type: string\n        from: a\n    response:\n      body: {}\n",
+    )
+    .expect("Failed to write config");
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "--config", config_path.to_str().unwrap()])
+        .output()
+        .expect("Failed to run server");
+
+    assert!(!output.status.success(), "Cyclic variables should fail");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("derivation cycle: a -> b -> a"),
+        "Error should name the cycle, got: {}",
+        stderr
+    );
+}
+
+#[tokio::test]
+async fn test_json5_config() {
+    let server = TestServer::start_with_config("tests/configs/commented.json5").await;
+
+    let body = server
+        .post_json("/greetings", json!({"name": "Ada"}))
+        .await
+        .expect("Failed to post greeting");
+    assert_eq!(body["message"], "Hello, Ada");
+    assert_eq!(body["format"], "json5");
+    drop(server);
+
+    // --config-format overrides the extension
+    let config_path = std::env::temp_dir().join("nugget-json5-config.conf");
+    std::fs::copy("tests/configs/commented.json5", &config_path).expect("Failed to copy config");
+    let server =
+        TestServer::start_with_args(config_path.to_str().unwrap(), &["--config-format", "json5"])
+            .await;
+    let body = server
+        .post_json("/greetings", json!({"name": "Grace"}))
+        .await
+        .expect("Failed to post greeting");
+    assert_eq!(body["message"], "Hello, Grace");
+}
+
+#[tokio::test]
+async fn test_seeded_objects_available_at_startup() {
+    let server = TestServer::start_with_config("tests/configs/seed.yaml").await;
+
+    let report = server
+        .get_json("/reports/orders")
+        .await
+        .expect("Failed to get orders report");
+
+    assert_eq!(report["all_orders"].as_array().unwrap().len(), 3);
+    assert_eq!(
+        report["customers"],
+        json!(["Seeded Alice", "Seeded Bob", "Seeded Carol"])
+    );
+
+    let customer = server
+        .get_json("/orders/seed-order-2/customer")
+        .await
+        .expect("Failed to get seeded order");
+    assert_eq!(customer["customer"], "Seeded Bob");
+}
+
+#[tokio::test]
+async fn test_for_each_template() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    let mut ids = Vec::new();
+    for (customer, total) in [("Alice", 100), ("Bob", 250)] {
+        let order = server
+            .post_json("/orders", json!({"customer": customer, "total": total}))
+            .await
+            .expect("Failed to create order");
+        ids.push(order["id"].clone());
+    }
+
+    let report = server
+        .get_json("/reports/orders/labels")
+        .await
+        .expect("Failed to get labels");
+    assert_eq!(
+        report["labels"],
+        json!([
+            {"id": ids[0], "label": "Alice - 100"},
+            {"id": ids[1], "label": "Bob - 250"}
+        ])
+    );
+
+    server.clear_state().await.expect("Failed to clear state");
+    let report = server
+        .get_json("/reports/orders/labels")
+        .await
+        .expect("Failed to get labels");
+    assert_eq!(report["labels"], json!([]));
+}
+
+#[tokio::test]
+async fn test_references_to_empty_types() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    let report = server
+        .get_json("/reports/orders")
+        .await
+        .expect("Failed to get orders report");
+    assert_eq!(report["all_orders"], json!([]));
+    assert_eq!(report["customers"], json!([]));
+    assert_eq!(report["total_revenue"], json!([]));
+
+    // Lookups by id fall back to the configured defaults for the projected field
+    let items = server
+        .get_json("/inventory/order/missing-order/items")
+        .await
+        .expect("Failed to get order items");
+    assert_eq!(items["order_id"], "missing-order");
+    assert_eq!(items["items"], json!([]));
+    assert_eq!(items["customer"], "Anonymous");
+}
+
+#[tokio::test]
+async fn test_route_matching_with_many_routes() {
+    let mut routes: Vec<Value> = (0..500)
+        .map(|i| {
+            json!({
+                "path": format!("/resources{}/{{id}}", i),
+                "method": "GET",
+                "response": { "status": 200, "body": { "resource": i, "id": "{path.id}" } }
+            })
+        })
+        .collect();
+    // A static route declared after a parameterised one on the same prefix
+    routes.push(json!({
+        "path": "/resources7/special",
+        "method": "GET",
+        "response": { "status": 200, "body": { "special": true } }
+    }));
+    routes.push(json!({
+        "path": "/resources8/special",
+        "method": "POST",
+        "response": { "status": 200, "body": { "special": true } }
+    }));
+
+    let config: nugget::Config =
+        serde_json::from_value(json!({ "routes": routes })).expect("Failed to build config");
+    let app = nugget::build_router(nugget::AppState::new(config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind listener");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let client = Client::new();
+    let response: Value = client
+        .get(format!("http://{}/resources499/abc", addr))
+        .send()
+        .await
+        .expect("Failed to call route")
+        .json()
+        .await
+        .expect("Failed to parse response");
+    assert_eq!(response["resource"], 499);
+    assert_eq!(response["id"], "abc");
+
+    // The earlier parameterised route wins, matching config order
+    let response: Value = client
+        .get(format!("http://{}/resources7/special", addr))
+        .send()
+        .await
+        .expect("Failed to call route")
+        .json()
+        .await
+        .expect("Failed to parse response");
+    assert_eq!(response["resource"], 7);
+
+    let response = client
+        .post(format!("http://{}/resources8/special", addr))
+        .send()
+        .await
+        .expect("Failed to call route");
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .get(format!("http://{}/unknown/path", addr))
+        .send()
+        .await
+        .expect("Failed to call route");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_json_patch_stored_object() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    let order = server
+        .post_json(
+            "/orders",
+            json!({"items": ["laptop"], "customer": "Patchy", "total": 10}),
+        )
+        .await
+        .expect("Failed to create order");
+    let id = order["id"].as_str().unwrap();
+
+    let client = Client::new();
+    let response = client
+        .patch(format!("{}/orders/{}", server.base_url, id))
+        .header("Content-Type", "application/json-patch+json")
+        .body(
+            json!([
+                {"op": "add", "path": "/items/-", "value": "mouse"},
+                {"op": "remove", "path": "/created_at"},
+                {"op": "replace", "path": "/total", "value": 25}
+            ])
+            .to_string(),
+        )
+        .send()
+        .await
+        .expect("Failed to patch order");
+    assert_eq!(response.status(), 200);
+
+    let patched: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(patched["items"], json!(["laptop", "mouse"]));
+    assert_eq!(patched["total"], 25);
+    assert!(patched.get("created_at").is_none());
+
+    // The stored object reflects the patch
+    let fetched = server
+        .get_json(&format!("/orders/{}", id))
+        .await
+        .expect("Failed to get order");
+    assert_eq!(fetched, patched);
+
+    // An operation that cannot be applied is rejected and changes nothing
+    let response = client
+        .patch(format!("{}/orders/{}", server.base_url, id))
+        .header("Content-Type", "application/json-patch+json")
+        .body(
+            json!([
+                {"op": "replace", "path": "/total", "value": 0},
+                {"op": "remove", "path": "/does_not_exist"}
+            ])
+            .to_string(),
+        )
+        .send()
+        .await
+        .expect("Failed to send invalid patch");
+    assert_eq!(response.status(), 422);
+
+    let fetched = server
+        .get_json(&format!("/orders/{}", id))
+        .await
+        .expect("Failed to get order");
+    assert_eq!(fetched["total"], 25);
+}
+
+#[tokio::test]
+async fn test_templated_patch_merges_then_renders() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+    let client = Client::new();
+
+    server
+        .post_json("/tickets", json!({"title": "Draft"}))
+        .await
+        .expect("Failed to create ticket");
+
+    let response = client
+        .patch(format!("{}/tickets/1", server.base_url))
+        .json(&json!({"title": "Final"}))
+        .send()
+        .await
+        .expect("Failed to patch ticket");
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.expect("Failed to parse response");
+    assert_eq!(body, json!({"updated": "1", "title": "Final"}));
+
+    // The merge still reached the store
+    let ticket = server
+        .get_json("/tickets/1")
+        .await
+        .expect("Failed to get ticket");
+    assert_eq!(ticket["title"], "Final");
+
+    let response = client
+        .patch(format!("{}/tickets/99", server.base_url))
+        .json(&json!({"title": "Nobody"}))
+        .send()
+        .await
+        .expect("Failed to patch ticket");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_put_patch_delete_stored_object() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let order = server
+        .post_json(
+            "/orders",
+            json!({"items": ["desk"], "customer": "Verbs", "total": 5}),
+        )
+        .await
+        .expect("Failed to create order");
+    let id = order["id"].as_str().unwrap();
+    let url = format!("{}/orders/{}", server.base_url, id);
+
+    // A plain JSON PATCH merges into the stored object; null removes a field
+    let client = Client::new();
+    let merged: Value = client
+        .patch(&url)
+        .json(&json!({"total": 7, "created_at": null}))
+        .send()
+        .await
+        .expect("Failed to merge order")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_eq!(merged["total"], 7);
+    assert_eq!(merged["customer"], "Verbs");
+    assert!(merged.get("created_at").is_none());
+
+    // PUT replaces the whole object
+    let response = client
+        .put(&url)
+        .json(&json!({"id": id, "customer": "Replaced"}))
+        .send()
+        .await
+        .expect("Failed to replace order");
+    assert_eq!(response.status(), 200);
+    let fetched = server.get_json(&format!("/orders/{id}")).await.unwrap();
+    assert_eq!(fetched, json!({"id": id, "customer": "Replaced"}));
+
+    // DELETE removes it, and its body is available to the template
+    let deleted: Value = client
+        .delete(&url)
+        .json(&json!({"reason": "duplicate"}))
+        .send()
+        .await
+        .expect("Failed to delete order")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_eq!(deleted, json!({"deleted": id, "reason": "duplicate"}));
+
+    let fetched = server.get_json(&format!("/orders/{id}")).await.unwrap();
+    assert_eq!(fetched["message"], "Order not found");
+
+    let response = client.delete(&url).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_lua_delete_body() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let client = Client::new();
+    let body: Value = client
+        .delete(format!("{}/sessions/s1", server.base_url))
+        .json(&json!({"reason": "logout"}))
+        .send()
+        .await
+        .expect("Failed to delete session")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_eq!(body, json!({"session": "s1", "reason": "logout"}));
+
+    let body: Value = client
+        .delete(format!("{}/sessions/s1", server.base_url))
+        .send()
+        .await
+        .expect("Failed to delete session")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_eq!(body["reason"], "none");
+}
+
+#[tokio::test]
+async fn test_fault_injection() {
+    let server = TestServer::start().await;
+
+    let response = server
+        .get_with_headers("/chaos/unavailable", vec![])
+        .await
+        .expect("Failed to call faulty route");
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "5");
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"], "Service unavailable");
+
+    let client = Client::new();
+    let mut faults = 0;
+    for _ in 0..100 {
+        let response = client
+            .get(format!("{}/chaos/flaky", server.base_url))
+            .send()
+            .await
+            .expect("Failed to call flaky route");
+        if response.status() == 503 {
+            faults += 1;
+        } else {
+            assert_eq!(response.status(), 200);
+        }
+    }
+    assert!(
+        (10..=50).contains(&faults),
+        "Expected roughly 30 faults out of 100, got {}",
+        faults
+    );
+}
+
+#[tokio::test]
+async fn test_accept_based_response_selection() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+    server
+        .post_json("/orders", json!({"customer": "Accept Tester", "total": 5}))
+        .await
+        .expect("Failed to create order");
+
+    let response = server
+        .get_with_headers(
+            "/reports/orders/summary",
+            vec![("Accept", "application/json")],
+        )
+        .await
+        .expect("Failed to get summary");
+    let summary: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(summary["customers"], json!(["Accept Tester"]));
+    assert!(summary.get("detailed").is_none());
+
+    let response = server
+        .get_with_headers(
+            "/reports/orders/summary",
+            vec![(
+                "Accept",
+                "application/json;q=0.5, application/vnd.nugget.detailed+json",
+            )],
+        )
+        .await
+        .expect("Failed to get detailed report");
+    let detailed: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(detailed["detailed"], true);
+    assert_eq!(detailed["orders"][0]["customer"], "Accept Tester");
+}
+
+#[tokio::test]
+async fn test_echo_reflects_request() {
+    let server = TestServer::start().await;
+
+    let echo: Value = Client::new()
+        .put(format!(
+            "{}/_echo/orders/42?tag=a&tag=b&page=2",
+            server.base_url
+        ))
+        .header("X-Trace", "abc123")
+        .json(&json!({"customer": "Alice", "items": [1, 2]}))
+        .send()
+        .await
+        .expect("Failed to send echo request")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+
+    assert_eq!(echo["method"], "PUT");
+    assert_eq!(echo["path"], "/_echo/orders/42");
+    assert_eq!(echo["query"], json!({"tag": ["a", "b"], "page": "2"}));
+    assert_eq!(echo["headers"]["x-trace"], "abc123");
+    assert_eq!(echo["headers"]["content-type"], "application/json");
+    assert_eq!(echo["body"], json!({"customer": "Alice", "items": [1, 2]}));
+    assert_eq!(echo["client_ip"], "127.0.0.1");
+    assert!(echo["received_at"].as_str().is_some());
+
+    let echo: Value = Client::new()
+        .post(format!("{}/_echo", server.base_url))
+        .body("not json")
+        .send()
+        .await
+        .expect("Failed to send echo request")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_eq!(echo["body"], "not json");
+}
+
+#[tokio::test]
+async fn test_fault_header_targets_fault() {
+    let server = TestServer::start().await;
+    let client = Client::new();
+    let url = format!("{}/chaos/orders", server.base_url);
+
+    let chaos = client
+        .get(&url)
+        .header("X-Chaos", "1")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(chaos.status(), 503);
+    let body: Value = chaos.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"], "Chaos requested");
+
+    let normal = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(normal.status(), 200);
+
+    let other_value = client
+        .get(&url)
+        .header("X-Chaos", "0")
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(other_value.status(), 200);
+}
+
+#[tokio::test]
+async fn test_flaky_route_succeeds_on_retry() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let client = Client::new();
+    let charge = |key: &'static str| {
+        client
+            .post(format!("{}/charges", server.base_url))
+            .header("Idempotency-Key", key)
+            .json(&json!({"amount": 25}))
+            .send()
+    };
+
+    let first = charge("charge-1").await.expect("Failed to send charge");
+    assert_eq!(first.status(), 500);
+
+    let retry = charge("charge-1").await.expect("Failed to retry charge");
+    assert_eq!(retry.status(), 201);
+    let retry: Value = retry.json().await.expect("Failed to parse JSON");
+    assert_eq!(retry["amount"], 25);
+
+    // The success is cached for later retries
+    let replay = charge("charge-1").await.expect("Failed to replay charge");
+    assert_eq!(replay.status(), 201);
+    assert_eq!(replay.headers()["idempotent-replayed"], "true");
+    let replay: Value = replay.json().await.expect("Failed to parse JSON");
+    assert_eq!(replay["id"], retry["id"]);
+
+    let other = charge("charge-2").await.expect("Failed to send charge");
+    assert_eq!(other.status(), 500);
+}
+
+#[tokio::test]
+async fn test_large_numbers_round_trip_unchanged() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let client = Client::new();
+    let created = client
+        .post(format!("{}/orders", server.base_url))
+        .header("Content-Type", "application/json")
+        .body(r#"{"customer": "Alice", "items": [0.1000000000000000055511151231257827], "total": 123456789012345678901234567890}"#)
+        .send()
+        .await
+        .expect("Failed to create order")
+        .text()
+        .await
+        .expect("Failed to read body");
+    assert!(
+        created.contains(r#""total":123456789012345678901234567890"#),
+        "{created}"
+    );
+    assert!(
+        created.contains("[0.1000000000000000055511151231257827]"),
+        "{created}"
+    );
+
+    let created: Value = serde_json::from_str(&created).expect("Failed to parse JSON");
+    let stored = client
+        .get(format!(
+            "{}/orders/{}",
+            server.base_url,
+            created["id"].as_str().unwrap()
+        ))
+        .send()
+        .await
+        .expect("Failed to get order")
+        .text()
+        .await
+        .expect("Failed to read body");
+    assert!(
+        stored.contains(r#""total":123456789012345678901234567890"#),
+        "{stored}"
+    );
+
+    let echoed = client
+        .post(format!("{}/_echo", server.base_url))
+        .header("Content-Type", "application/json")
+        .body(r#"{"id": 9999999999999999, "ratio": 1.10}"#)
+        .send()
+        .await
+        .expect("Failed to echo")
+        .text()
+        .await
+        .expect("Failed to read body");
+    assert!(
+        echoed.contains(r#""body":{"id":9999999999999999,"ratio":1.10}"#),
+        "{echoed}"
+    );
+}
+
+#[tokio::test]
+async fn test_idempotency_key_replays_first_response() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    let client = Client::new();
+    let post_order = |key: &'static str, customer: &'static str| {
+        client
+            .post(format!("{}/orders", server.base_url))
+            .header("Idempotency-Key", key)
+            .json(&json!({"customer": customer, "total": 10}))
+            .send()
+    };
+
+    let first = post_order("retry-1", "Alice")
+        .await
+        .expect("Failed to create order");
+    assert_eq!(first.status(), 201);
+    let first: Value = first.json().await.expect("Failed to parse JSON");
+
+    let retry = post_order("retry-1", "Alice")
+        .await
+        .expect("Failed to retry order");
+    assert_eq!(retry.status(), 201);
+    assert_eq!(retry.headers()["idempotent-replayed"], "true");
+    let retry: Value = retry.json().await.expect("Failed to parse JSON");
+    assert_eq!(retry, first, "Retries should replay the first response");
+
+    let report = server
+        .get_json("/reports/orders")
+        .await
+        .expect("Failed to get orders report");
+    assert_eq!(report["all_orders"].as_array().unwrap().len(), 1);
+
+    // A different key is a new request
+    let other: Value = post_order("retry-2", "Bob")
+        .await
+        .expect("Failed to create second order")
+        .json()
+        .await
+        .expect("Failed to parse JSON");
+    assert_ne!(other["id"], first["id"]);
+}
+
+#[tokio::test]
+async fn test_strict_methods_reject_undeclared_verbs() {
+    let server = TestServer::start_with_config("tests/configs/strict-methods.yaml").await;
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}/widgets", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get widgets");
+    assert_eq!(response.status(), 200);
+
+    // Declared paths answer other verbs with 405 instead of handling or proxying them
+    let response = client
+        .post(format!("{}/widgets", server.base_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .expect("Failed to post widgets");
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "GET");
+
+    let response = client
+        .get(format!("{}/widgets/42", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get widget");
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "DELETE");
+
+    let response = client
+        .delete(format!("{}/widgets/42", server.base_url))
+        .send()
+        .await
+        .expect("Failed to delete widget");
+    assert_eq!(response.status(), 204);
+
+    // Unknown paths still go to the proxy, which is unreachable here
+    let response = client
+        .get(format!("{}/elsewhere", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get unknown path");
+    assert_eq!(response.status(), 502);
+}
+
+#[tokio::test]
+async fn test_custom_reason_phrase() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = TestServer::start().await;
+
+    // reqwest only exposes canonical reasons, so read the raw status line
+    let address = server.base_url.trim_start_matches("http://");
+    let mut stream = tokio::net::TcpStream::connect(address)
+        .await
+        .expect("Failed to connect");
+    stream
+        .write_all(b"GET /teapot HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .expect("Failed to send request");
+
+    let mut raw = String::new();
+    stream
+        .read_to_string(&mut raw)
+        .await
+        .expect("Failed to read response");
+    assert!(
+        raw.starts_with("HTTP/1.1 418 Short And Stout\r\n"),
+        "Unexpected status line in: {raw}"
+    );
+    assert!(raw.contains("I'm a teapot"));
+}
+
+#[tokio::test]
+async fn test_streamed_response_arrives_in_chunks() {
+    let server = TestServer::start().await;
+
+    let started = std::time::Instant::now();
+    let mut response = Client::new()
+        .get(format!("{}/slow-report", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get slow report");
+    assert_eq!(response.status(), 200);
+
+    let mut body = Vec::new();
+    let mut arrivals = Vec::new();
+    while let Some(chunk) = response.chunk().await.expect("Failed to read chunk") {
+        assert!(chunk.len() <= 8, "Chunks should respect chunk_size");
+        body.extend_from_slice(&chunk);
+        arrivals.push(started.elapsed());
+    }
+
+    let body: Value = serde_json::from_slice(&body).expect("Failed to parse streamed JSON");
+    assert_eq!(body["report"], "quarterly");
+    assert_eq!(body["rows"], json!([1, 2, 3, 4, 5]));
+
+    assert!(
+        arrivals.len() > 3,
+        "Expected several chunks, got {}",
+        arrivals.len()
+    );
+    let spread = *arrivals.last().unwrap() - arrivals[0];
+    assert!(
+        spread >= std::time::Duration::from_millis(300),
+        "Chunks should arrive over time, spread was {spread:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_pretty_printed_responses() {
+    let server = TestServer::start_with_args("config.yaml", &["--pretty"]).await;
+
+    let response = Client::new()
+        .get(format!("{}/health", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get health");
+    assert_eq!(response.headers()["content-type"], "application/json");
+
+    let text = response.text().await.expect("Failed to read body");
+    assert!(
+        text.contains("\n  \"status\": \"healthy\""),
+        "Body should be indented, got: {text}"
+    );
+    let body: Value = serde_json::from_str(&text).expect("Failed to parse JSON");
+    assert_eq!(body["status"], "healthy");
+}
+
+#[tokio::test]
+async fn test_multiple_params_in_one_segment() {
+    let server = TestServer::start().await;
+
+    let coords = server
+        .get_json("/coords/40-70")
+        .await
+        .expect("Failed to get coords");
+    assert_eq!(coords["lat"], "40");
+    assert_eq!(coords["lng"], "70");
+
+    let coords = server
+        .get_json("/coords/-33.9-151.2")
+        .await
+        .expect("Failed to get negative coords");
+    assert_eq!(coords["lat"], "-33.9");
+    assert_eq!(coords["lng"], "151.2");
+
+    let file = server
+        .get_json("/files/report.pdf")
+        .await
+        .expect("Failed to get file");
+    assert_eq!(file["name"], "report");
+    assert_eq!(file["ext"], "pdf");
+
+    // Segments missing the separator don't match
+    let response = server
+        .get_with_headers("/coords/4070", vec![])
+        .await
+        .expect("Failed to request coords");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_async_job_transitions_to_done() {
+    let server = TestServer::start().await;
+
+    let response = Client::new()
+        .post(format!("{}/exports", server.base_url))
+        .header("Prefer", "respond-async")
+        .json(&json!({"format": "csv"}))
+        .send()
+        .await
+        .expect("Failed to start export");
+    assert_eq!(response.status(), 202);
+    assert_eq!(response.headers()["preference-applied"], "respond-async");
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    assert!(location.starts_with("/_jobs/"));
+
+    let job = server
+        .get_json(&location)
+        .await
+        .expect("Failed to poll job");
+    assert_eq!(job["status"], "processing");
+    assert!(job.get("result").is_none());
+
+    sleep(Duration::from_millis(400)).await;
+    let job = server
+        .get_json(&location)
+        .await
+        .expect("Failed to poll job");
+    assert_eq!(job["status"], "done");
+    assert_eq!(job["result"], json!({"format": "csv", "rows": 42}));
+
+    let response = server
+        .get_with_headers("/_jobs/unknown", vec![])
+        .await
+        .expect("Failed to poll unknown job");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_config_dir_merges_files() {
+    let server =
+        TestServer::start_with_args("config.yaml", &["--config-dir", "tests/configs/config-dir"])
+            .await;
+
+    let users = server
+        .get_json("/users")
+        .await
+        .expect("Failed to get users");
+    assert_eq!(users["source"], "03-users.json");
+
+    // Files load in filename order, so the earlier definition of a route wins
+    let orders = server
+        .get_json("/orders")
+        .await
+        .expect("Failed to get orders");
+    assert_eq!(orders["source"], "02-orders.yaml");
+
+    // Routes from config.yaml aren't loaded alongside the directory
+    let response = server
+        .get_with_headers("/teapot", vec![])
+        .await
+        .expect("Failed to request teapot");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_bulk_insert_objects() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let mut orders: Vec<Value> = (0..49)
+        .map(|i| json!({"customer": format!("Bulk {i}"), "total": i}))
+        .collect();
+    orders.push(json!({"id": "known-id", "customer": "Bulk 49", "total": 49}));
+
+    let response = Client::new()
+        .post(format!("{}/state/objects/orders/bulk", server.base_url))
+        .json(&orders)
+        .send()
+        .await
+        .expect("Failed to bulk insert");
+    assert_eq!(response.status(), 201);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["count"], 50);
+    let ids = body["ids"].as_array().unwrap();
+    assert_eq!(ids.len(), 50);
+    assert_eq!(ids[49], "known-id");
+
+    let report = server
+        .get_json("/reports/orders")
+        .await
+        .expect("Failed to get report");
+    assert_eq!(report["all_orders"].as_array().unwrap().len(), 50);
+    assert_eq!(report["all_orders"][0]["id"], ids[0]);
+    assert_eq!(report["customers"][49], "Bulk 49");
+
+    let response = Client::new()
+        .post(format!("{}/state/objects/orders/bulk", server.base_url))
+        .json(&json!({"customer": "Not a list"}))
+        .send()
+        .await
+        .expect("Failed to send bulk insert");
+    assert_eq!(response.status(), 422);
+}
+
+#[tokio::test]
+async fn test_numeric_param_conditions() {
+    let server = TestServer::start().await;
+
+    let legacy = server
+        .get_json("/items/500")
+        .await
+        .expect("Failed to get legacy item");
+    assert_eq!(legacy["handler"], "legacy");
+    assert_eq!(legacy["id"], "500");
+
+    let new = server
+        .get_json("/items/5000")
+        .await
+        .expect("Failed to get new item");
+    assert_eq!(new["handler"], "new");
+
+    let boundary = server
+        .get_json("/items/1000")
+        .await
+        .expect("Failed to get boundary item");
+    assert_eq!(boundary["handler"], "new");
+
+    // Non-numeric ids satisfy neither condition
+    let response = server
+        .get_with_headers("/items/abc", vec![])
+        .await
+        .expect("Failed to request item");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_clear_state_reports_counts() {
+    let server = TestServer::start().await;
+
+    server.clear_state().await.expect("Failed to clear state");
+    for customer in ["Alice", "Bob"] {
+        server
+            .post_json("/orders", json!({"customer": customer}))
+            .await
+            .expect("Failed to create order");
+    }
+    server
+        .post_json("/users", json!({"username": "carol"}))
+        .await
+        .expect("Failed to create user");
+
+    let cleared = server.clear_state().await.expect("Failed to clear state");
+    assert_eq!(cleared["status"], "cleared");
+    assert_eq!(
+        cleared["cleared"]["objects"],
+        json!({"orders": 2, "users": 1})
+    );
+    assert_eq!(cleared["cleared"]["storage"], 3);
+    assert_eq!(cleared["cleared"]["lua_state"], 0);
+
+    let cleared = server.clear_state().await.expect("Failed to clear state");
+    assert_eq!(cleared["cleared"]["objects"], json!({}));
+}
+
+#[tokio::test]
+async fn test_clear_state_token_and_custom_response() {
+    let server = TestServer::start_with_config("tests/configs/clear-state.yaml").await;
+    let client = Client::new();
+    let url = format!("{}/state/clear", server.base_url);
+
+    let response = client.post(&url).send().await.expect("Failed to clear");
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .post(&url)
+        .bearer_auth("wrong")
+        .send()
+        .await
+        .expect("Failed to clear");
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .post(&url)
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .expect("Failed to clear");
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["status"], "reset");
+    assert_eq!(body["message"], "Fixture state wiped");
+    assert_eq!(body["cleared"]["objects"], json!({}));
+}
+
+#[tokio::test]
+async fn test_field_pattern_validation() {
+    let server = TestServer::start().await;
+    let client = Client::new();
+    let url = format!("{}/signups", server.base_url);
+
+    let response = client
+        .post(&url)
+        .json(&json!({"email": "ada@example.com", "zip": "12345"}))
+        .send()
+        .await
+        .expect("Failed to sign up");
+    assert_eq!(response.status(), 201);
+
+    let response = client
+        .post(&url)
+        .json(&json!({"email": "not-an-email", "zip": "12345"}))
+        .send()
+        .await
+        .expect("Failed to sign up");
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["field"], "email");
+
+    // Patterns must match the whole value
+    let response = client
+        .post(&url)
+        .json(&json!({"email": "ada@example.com", "zip": "123456"}))
+        .send()
+        .await
+        .expect("Failed to sign up");
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["field"], "zip");
+}
+
+#[tokio::test]
+async fn test_invalid_field_pattern_is_rejected_up_front() {
+    let server = TestServer::start_with_args("config.yaml", &["--admin-routes"]).await;
+    let route = json!({
+        "path": "/runtime/signups",
+        "method": "POST",
+        "field_patterns": {"zip": "[0-9"},
+        "response": {"status": 201, "body": {}}
+    });
+
+    let response = Client::new()
+        .post(format!("{}/admin/routes", server.base_url))
+        .json(&route)
+        .send()
+        .await
+        .expect("Failed to register route");
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    let error = body["error"].as_str().unwrap();
+    assert!(
+        error.contains("invalid pattern for field 'zip'"),
+        "Unexpected error: {error}"
+    );
+}
+
+#[tokio::test]
+async fn test_timeout_returns_gateway_timeout() {
+    use std::time::{Duration, Instant};
+
+    let server = TestServer::start().await;
+    let started = Instant::now();
+    let response = Client::new()
+        .get(format!("{}/test/timeout", server.base_url))
+        .send()
+        .await
+        .expect("Failed to send request");
+
+    assert_eq!(response.status(), 504);
+    assert!(
+        started.elapsed() < Duration::from_millis(900),
+        "Timed out response took {:?}",
+        started.elapsed()
+    );
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"], "Request timed out after 200ms");
+}
+
+#[tokio::test]
+async fn test_context_interpolation() {
+    let server = TestServer::start().await;
+
+    let body = server
+        .get_json("/test/context")
+        .await
+        .expect("Failed to get context");
+    assert_eq!(body["url"], "https://api.example.com/orders");
+    assert_eq!(body["version"], 2);
+    assert_eq!(body["unknown"], "{context.missing}");
+}
+
+#[tokio::test]
+async fn test_env_interpolation_requires_flag() {
+    // `cargo run` hands the server CARGO_PKG_NAME
+    let server = TestServer::start_with_args("config.yaml", &["--allow-env"]).await;
+    let body = server
+        .get_json("/test/env")
+        .await
+        .expect("Failed to get env");
+    assert_eq!(body["package"], "nugget");
+    assert_eq!(body["greeting"], "Served by nugget");
+    assert_eq!(body["unset"], "{env.NUGGET_SURELY_UNSET_VARIABLE}");
+    drop(server);
+
+    let server = TestServer::start().await;
+    let body = server
+        .get_json("/test/env")
+        .await
+        .expect("Failed to get env");
+    assert_eq!(body["package"], "{env.CARGO_PKG_NAME}");
+}
+
+#[tokio::test]
+async fn test_param_cases() {
+    let server = TestServer::start().await;
+
+    let me = server
+        .get_json("/users/me")
+        .await
+        .expect("Failed to get me");
+    assert_eq!(me["username"], "admin");
+    assert_eq!(me["role"], "admin");
+
+    let other = server
+        .get_json("/users/someone-else")
+        .await
+        .expect("Failed to get user");
+    assert_eq!(other["message"], "User not found");
+}
+
+#[tokio::test]
+async fn test_first_and_last_object_references() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let empty = server
+        .get_json("/reports/orders/latest")
+        .await
+        .expect("Failed to get report");
+    assert_eq!(empty["first"], Value::Null);
+    assert_eq!(empty["last"], Value::Null);
+    assert_eq!(empty["last_customer"], "Anonymous");
+
+    let mut ids = Vec::new();
+    for customer in ["Ada", "Grace", "Linus"] {
+        let order = server
+            .post_json(
+                "/orders",
+                json!({"customer": customer, "items": [], "total": 1}),
+            )
+            .await
+            .expect("Failed to create order");
+        ids.push(order["id"].clone());
+    }
+
+    let report = server
+        .get_json("/reports/orders/latest")
+        .await
+        .expect("Failed to get report");
+    assert_eq!(report["first"]["id"], ids[0]);
+    assert_eq!(report["first"]["customer"], "Ada");
+    assert_eq!(report["last"]["id"], ids[2]);
+    assert_eq!(report["last"]["customer"], "Linus");
+    assert_eq!(report["last_customer"], "Linus");
+}
+
+#[tokio::test]
+async fn test_fields_named_first_and_last_win_over_positions() {
+    let server = TestServer::start_with_config("tests/configs/seed.yaml").await;
+
+    let report = server
+        .get_json("/reports/people")
+        .await
+        .expect("Failed to get report");
+    assert_eq!(report["first_names"], json!(["Ada", "Grace"]));
+    assert_eq!(report["last_names"], json!(["Lovelace", "Hopper"]));
+    // Types without such fields still get the position meaning
+    assert_eq!(report["oldest_order"], "Seeded Alice");
+}
+
+#[tokio::test]
+async fn test_response_cache() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+    let client = Client::new();
+    let quote = |symbol: &str| {
+        client
+            .post(format!("{}/quotes", server.base_url))
+            .json(&json!({"symbol": symbol}))
+            .send()
+    };
+
+    let first = quote("ACME").await.expect("Failed to get quote");
+    assert_eq!(first.headers()["x-cache"], "MISS");
+    let first: Value = first.json().await.unwrap();
+
+    let second = quote("ACME").await.expect("Failed to get quote");
+    assert_eq!(second.headers()["x-cache"], "HIT");
+    assert_eq!(second.json::<Value>().await.unwrap(), first);
+
+    // A different body is a different request
+    let other = quote("INIT").await.expect("Failed to get quote");
+    assert_eq!(other.headers()["x-cache"], "MISS");
+    assert_ne!(other.json::<Value>().await.unwrap()["id"], first["id"]);
+
+    sleep(Duration::from_millis(600)).await;
+    let expired = quote("ACME").await.expect("Failed to get quote");
+    assert_eq!(expired.headers()["x-cache"], "MISS");
+    assert_ne!(expired.json::<Value>().await.unwrap()["id"], first["id"]);
+}
+
+#[tokio::test]
+async fn test_etag_not_modified() {
+    let server = TestServer::start().await;
+
+    let response = server
+        .get_with_headers("/settings", vec![])
+        .await
+        .expect("Failed to get settings");
+    assert_eq!(response.status(), 200);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    assert!(etag.starts_with('"') && etag.ends_with('"'), "got {etag}");
+
+    let response = server
+        .get_with_headers("/settings", vec![("If-None-Match", &etag)])
+        .await
+        .expect("Failed to revalidate settings");
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = server
+        .get_with_headers("/settings", vec![("If-None-Match", "\"stale\"")])
+        .await
+        .expect("Failed to get settings");
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["theme"], "dark");
+
+    // Routes without etag send none
+    let response = server
+        .get_with_headers("/health", vec![])
+        .await
+        .expect("Failed to get health");
+    assert!(response.headers().get("etag").is_none());
+}
+
+#[tokio::test]
+async fn test_object_schema_rejects_bad_objects() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/invoices", server.base_url))
+        .json(&json!({"customer": "Ada", "total": "lots"}))
+        .send()
+        .await
+        .expect("Failed to post invoice");
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["path"], "/total");
+
+    let invoices = server.get_json("/invoices").await.expect("Failed to list");
+    assert_eq!(invoices, json!([]));
+
+    let invoice = server
+        .post_json("/invoices", json!({"customer": "Ada", "total": 12.5}))
+        .await
+        .expect("Failed to post invoice");
+    assert_eq!(invoice["total"], 12.5);
+    let invoices = server.get_json("/invoices").await.expect("Failed to list");
+    assert_eq!(invoices.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_object_schema_rejection_leaves_sequences_alone() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let response = Client::new()
+        .post(format!("{}/receipts", server.base_url))
+        .json(&json!({"amount": "five"}))
+        .send()
+        .await
+        .expect("Failed to post receipt");
+    assert_eq!(response.status(), 422);
+
+    let receipt = server
+        .post_json("/receipts", json!({"amount": 5}))
+        .await
+        .expect("Failed to post receipt");
+    assert_eq!(receipt["id"], 1);
+    assert_eq!(receipt["number"], 100);
+}
+
+#[tokio::test]
+async fn test_payload_length_interpolation() {
+    let server = TestServer::start().await;
+
+    let response = server
+        .post_json(
+            "/test/length",
+            json!({"items": ["a", "b", "c"], "customer": "Ada"}),
+        )
+        .await
+        .expect("Failed to post");
+    assert_eq!(response["item_count"], 3);
+    assert_eq!(response["name_length"], 3);
+    assert_eq!(response["summary"], "3 items for Ada");
+
+    // Missing fields fall back to their defaults, here `items: []`
+    let response = server
+        .post_json("/test/length", json!({"customer": "Grace"}))
+        .await
+        .expect("Failed to post");
+    assert_eq!(response["item_count"], 0);
+}
+
+#[tokio::test]
+async fn test_conditional_interpolation() {
+    let server = TestServer::start().await;
+
+    let vip = server
+        .post_json("/test/conditional", json!({"vip": true, "name": "Ada"}))
+        .await
+        .expect("Failed to post");
+    assert_eq!(vip["tier"], "priority");
+    assert_eq!(vip["discount"], 10);
+    assert_eq!(vip["greeting"], "Hello, valued customer!");
+
+    let regular = server
+        .post_json("/test/conditional", json!({"vip": false, "name": "Ada"}))
+        .await
+        .expect("Failed to post");
+    assert_eq!(regular["tier"], "standard");
+    assert_eq!(regular["discount"], 0);
+    assert_eq!(regular["greeting"], "Hello, Ada!");
+
+    let absent = server
+        .post_json("/test/conditional", json!({"name": "Grace"}))
+        .await
+        .expect("Failed to post");
+    assert_eq!(absent["tier"], "standard");
+    assert_eq!(absent["greeting"], "Hello, Grace!");
+}
+
+#[tokio::test]
+async fn test_storage_without_id_source() {
+    let server = TestServer::start_capturing_stdout("config.yaml", &[]).await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let bookmark = server
+        .post_json("/bookmarks", json!({"url": "https://example.com"}))
+        .await
+        .expect("Failed to create bookmark");
+    let id = bookmark["id"].as_str().expect("Bookmark should get an id");
+    assert!(uuid::Uuid::parse_str(id).is_ok(), "not a UUID: {id}");
+
+    let stored = server
+        .get_json(&format!("/bookmarks/{id}"))
+        .await
+        .expect("Failed to get bookmark");
+    assert_eq!(stored["url"], "https://example.com");
+    assert_eq!(stored["id"], id);
+
+    let output = server.stop();
+    assert!(
+        output
+            .lines()
+            .any(|line| line.contains("WARN") && line.contains("POST /bookmarks: stores objects")),
+        "got: {output}"
+    );
+}
+
+#[tokio::test]
+async fn test_timestamp_variable() {
+    let server = TestServer::start().await;
+
+    let before = chrono::Utc::now();
+    let response = server
+        .post_json("/test/variables/timestamp", json!({}))
+        .await
+        .expect("Failed to test timestamp variables");
+    let after = chrono::Utc::now();
+
+    let parse = |field: &str| {
+        chrono::DateTime::parse_from_rfc3339(response[field].as_str().unwrap())
+            .expect("Invalid timestamp")
+    };
+    // RFC 3339 output has second precision, so allow for the truncated fraction
+    let created_at = parse("created_at");
+    assert!(created_at >= before - chrono::TimeDelta::seconds(1) && created_at <= after);
+
+    let yesterday = parse("yesterday");
+    let shift = created_at - yesterday;
+    assert!(
+        (86399..=86401).contains(&shift.num_seconds()),
+        "yesterday should be a day earlier, got {shift}"
+    );
+
+    let day = response["day"].as_str().unwrap();
+    chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").expect("Invalid formatted day");
+}
+
+#[tokio::test]
+async fn test_sequence_variable() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for n in 0..3 {
+        let response = server
+            .post_json("/test/variables/sequence", json!({}))
+            .await
+            .expect("Failed to test sequence variables");
+        assert_eq!(response["id"], n + 1);
+        assert_eq!(response["invoice_number"], 1000 + n * 10);
+    }
+
+    // Clearing state restarts the counters
+    server.clear_state().await.expect("Failed to clear state");
+    let response = server
+        .post_json("/test/variables/sequence", json!({}))
+        .await
+        .expect("Failed to test sequence variables");
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["invoice_number"], 1000);
+}
+
+#[tokio::test]
+async fn test_sequence_time_variable() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let mut times = Vec::new();
+    for _ in 0..3 {
+        let event = server
+            .post_json("/test/variables/sequence-time", json!({}))
+            .await
+            .expect("Failed to create event");
+        let time = event["recorded_at"]
+            .as_str()
+            .expect("Timestamp should be a string");
+        times.push(chrono::DateTime::parse_from_rfc3339(time).expect("Invalid timestamp"));
+    }
+
+    assert_eq!(times[0].to_rfc3339(), "2024-03-01T09:00:00+00:00");
+    assert_eq!((times[1] - times[0]).num_seconds(), 300);
+    assert_eq!((times[2] - times[1]).num_seconds(), 300);
+
+    // Clearing state restarts the sequence
+    server.clear_state().await.expect("Failed to clear state");
+    let event = server
+        .post_json("/test/variables/sequence-time", json!({}))
+        .await
+        .expect("Failed to create event");
+    assert_eq!(event["recorded_at"], "2024-03-01T09:00:00Z");
+}
+
+#[tokio::test]
+async fn test_preview_has_no_side_effects() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/_preview", server.base_url))
+        .json(&json!({
+            "route_path": "/orders",
+            "method": "POST",
+            "body": {"customer": "Ada", "items": ["widget"], "total": 5},
+            "headers": {"X-Trace": "preview"}
+        }))
+        .send()
+        .await
+        .expect("Failed to preview");
+    assert_eq!(response.status(), 201);
+    let order: Value = response.json().await.unwrap();
+    assert_eq!(order["customer"], "Ada");
+    assert_eq!(order["status"], "pending");
+    assert!(order["id"].as_str().is_some());
+
+    let health = server
+        .get_json("/health")
+        .await
+        .expect("Failed to get health");
+    assert_eq!(health["objects_stored"]["orders"], json!([]));
+
+    // Sequential ids aren't consumed either
+    for _ in 0..2 {
+        let ticket: Value = client
+            .post(format!("{}/_preview", server.base_url))
+            .json(&json!({"route_path": "/tickets", "method": "POST", "body": {"title": "t"}}))
+            .send()
+            .await
+            .expect("Failed to preview")
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(ticket["id"], 1);
+    }
+    let ticket = server
+        .post_json("/tickets", json!({"title": "real"}))
+        .await
+        .expect("Failed to create ticket");
+    assert_eq!(ticket["id"], 1);
+}
+
+#[tokio::test]
+async fn test_binary_response_body() {
+    use base64::Engine;
+
+    let server = TestServer::start().await;
+
+    let response = Client::new()
+        .get(format!("{}/images/pixel.png", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get image");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/png");
+
+    let bytes = response.bytes().await.expect("Failed to read image");
+    let expected = base64::prelude::BASE64_STANDARD
+        .decode("iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==")
+        .unwrap();
+    assert_eq!(bytes.as_ref(), expected.as_slice());
+    assert!(bytes.starts_with(b"\x89PNG\r\n\x1a\n"));
+}
+
+#[tokio::test]
+async fn test_response_case_camel() {
+    let server = TestServer::start().await;
+
+    let body = server
+        .get_json("/test/response-case")
+        .await
+        .expect("Failed to get response");
+
+    assert_eq!(
+        body,
+        json!({
+            "orderId": "A-1",
+            "shippingAddress": {"streetName": "Main St", "postalCode": "12345"},
+            "lineItems": [{"itemSku": "W-1", "unitPrice": 5}]
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_ndjson_list_format() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for n in 0..25 {
+        server
+            .post_json(
+                "/orders",
+                json!({"customer": format!("Customer {n}"), "items": [], "total": n}),
+            )
+            .await
+            .expect("Failed to create order");
+    }
+
+    let response = Client::new()
+        .get(format!("{}/feeds/orders", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get feed");
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let text = response.text().await.expect("Failed to read feed");
+
+    let lines: Vec<Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Each line should be JSON"))
+        .collect();
+    assert_eq!(lines.len(), 25);
+    assert_eq!(lines[0]["customer"], "Customer 0");
+    assert_eq!(lines[24]["total"], 24);
+    assert!(text.ends_with('\n'));
+}
+
+#[tokio::test]
+async fn test_shuffle_objects() {
+    let server =
+        TestServer::start_with_args("config.yaml", &["--shuffle-objects", "--seed", "7"]).await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for n in 0..8 {
+        server
+            .post_json(
+                "/orders",
+                json!({"customer": format!("Customer {n}"), "items": [], "total": n}),
+            )
+            .await
+            .expect("Failed to create order");
+    }
+
+    let client = Client::new();
+    let mut orders = Vec::new();
+    for _ in 0..5 {
+        let text = client
+            .get(format!("{}/feeds/orders", server.base_url))
+            .send()
+            .await
+            .expect("Failed to get feed")
+            .text()
+            .await
+            .expect("Failed to read feed");
+        let totals: Vec<i64> = text
+            .lines()
+            .map(|line| {
+                let order: Value = serde_json::from_str(line).expect("Each line should be JSON");
+                order["total"].as_i64().unwrap()
+            })
+            .collect();
+        orders.push(totals);
+    }
+
+    // Same orders every time, but not always in the same sequence
+    for totals in &orders {
+        let mut sorted = totals.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<i64>>());
+    }
+    assert!(orders.iter().any(|totals| totals != &orders[0]));
+}
+
+#[tokio::test]
+async fn test_payload_limits() {
+    let server = TestServer::start().await;
+    let client = Client::new();
+    let url = format!("{}/test/payload-limits", server.base_url);
+
+    let response = client
+        .post(&url)
+        .json(&json!({"name": "ok", "nested": {"inner": [1, 2]}}))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.expect("Failed to parse response");
+    assert_eq!(body["received"], "ok");
+
+    let response = client
+        .post(&url)
+        .json(&json!({"name": "deep", "a": {"b": {"c": {"d": 1}}}}))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.expect("Failed to parse response");
+    assert_eq!(
+        body["error"],
+        "Request payload nesting exceeds the route's max_payload_depth of 3"
+    );
+
+    let wide: serde_json::Map<String, Value> =
+        (0..11).map(|n| (format!("field{n}"), json!(n))).collect();
+    let response = client
+        .post(&url)
+        .json(&wide)
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.expect("Failed to parse response");
+    assert!(
+        body["error"]
+            .as_str()
+            .unwrap()
+            .contains("max_payload_fields of 10")
+    );
+}
+
+#[tokio::test]
+async fn test_paginated_object_list() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for n in 0..25 {
+        server
+            .post_json(
+                "/orders",
+                json!({"customer": format!("Customer {n}"), "items": [], "total": n}),
+            )
+            .await
+            .expect("Failed to create order");
+    }
+
+    let page = server
+        .get_json("/orders?page=2&per_page=10")
+        .await
+        .expect("Failed to get page");
+    assert_eq!(page["page"], 2);
+    assert_eq!(page["per_page"], 10);
+    assert_eq!(page["total"], 25);
+    let data = page["data"].as_array().unwrap();
+    assert_eq!(data.len(), 10);
+    assert_eq!(data[0]["total"], 10);
+    assert_eq!(data[9]["total"], 19);
+
+    // The route's per_page applies by default, and the last page is partial
+    let page = server
+        .get_json("/orders?page=3")
+        .await
+        .expect("Failed to get page");
+    assert_eq!(page["per_page"], 10);
+    assert_eq!(page["data"].as_array().unwrap().len(), 5);
+
+    let page = server
+        .get_json("/orders?page=4")
+        .await
+        .expect("Failed to get page");
+    assert_eq!(page["data"], json!([]));
+
+    let response = server
+        .get_with_headers("/orders?page=0", vec![])
+        .await
+        .expect("Failed to get page");
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn test_reference_lookup_by_path_parameter() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let first = server
+        .post_json(
+            "/orders",
+            json!({"customer": "Alice", "items": [{"sku": "A-1"}], "total": 10}),
+        )
+        .await
+        .expect("Failed to create order");
+    let second = server
+        .post_json(
+            "/orders",
+            json!({"customer": "Bob", "items": [{"sku": "B-1"}, {"sku": "B-2"}], "total": 20}),
+        )
+        .await
+        .expect("Failed to create order");
+
+    for order in [&first, &second] {
+        let id = order["id"].as_str().unwrap();
+        let response = server
+            .get_json(&format!("/inventory/order/{id}/items"))
+            .await
+            .expect("Failed to get order items");
+        assert_eq!(response["order_id"], id);
+        assert_eq!(response["items"], order["items"]);
+    }
+
+    // With orders stored, an unknown id still falls back to the defaults rather than
+    // leaving the reference unresolved
+    let response = server
+        .get_json("/inventory/order/unknown/items")
+        .await
+        .expect("Failed to get order items");
+    assert_eq!(response["items"], json!([]));
+    assert_eq!(response["customer"], "Anonymous");
+
+    // So does a known order that lacks the field
+    let id = first["id"].as_str().unwrap();
+    Client::new()
+        .put(format!("{}/orders/{id}", server.base_url))
+        .json(&json!({"id": id, "items": ["replacement"]}))
+        .send()
+        .await
+        .expect("Failed to replace order");
+    let response = server
+        .get_json(&format!("/inventory/order/{id}/items"))
+        .await
+        .expect("Failed to get order items");
+    assert_eq!(response["items"], json!(["replacement"]));
+    assert_eq!(response["customer"], "Anonymous");
+}
+
+#[tokio::test]
+async fn test_id_strategies() {
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for (n, title) in ["First", "Second", "Third"].into_iter().enumerate() {
+        let created = server
+            .post_json("/tickets", json!({"title": title}))
+            .await
+            .expect("Failed to create ticket");
+        assert_eq!(created["id"], n + 1);
+    }
+    for (id, title) in [(1, "First"), (2, "Second"), (3, "Third")] {
+        let ticket = server
+            .get_json(&format!("/tickets/{id}"))
+            .await
+            .expect("Failed to get ticket");
+        assert_eq!(ticket["title"], title);
+    }
+
+    // Clearing state restarts the sequence
+    server.clear_state().await.expect("Failed to clear state");
+    let created = server
+        .post_json("/tickets", json!({"title": "Again"}))
+        .await
+        .expect("Failed to create ticket");
+    assert_eq!(created["id"], 1);
+
+    server
+        .post_json("/accounts", json!({"username": "ada"}))
+        .await
+        .expect("Failed to create account");
+    let account = server
+        .get_json("/accounts/ada")
+        .await
+        .expect("Failed to get account");
+    assert_eq!(account["username"], "ada");
+
+    let response = Client::new()
+        .post(format!("{}/accounts", server.base_url))
+        .json(&json!({"name": "No username"}))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 422);
+}
+
+#[tokio::test]
+async fn test_cold_start_then_warm_latency() {
+    use std::time::{Duration, Instant};
+
+    let server = TestServer::start().await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let timed_search = || async {
+        let started = Instant::now();
+        server.get_json("/search").await.expect("Failed to search");
+        started.elapsed()
+    };
+
+    let cold = timed_search().await;
+    assert!(
+        cold >= Duration::from_millis(800),
+        "First request took {cold:?}"
+    );
+
+    let warm = timed_search().await;
+    assert!(
+        warm < Duration::from_millis(400),
+        "Second request took {warm:?}"
+    );
+
+    // Clearing state makes the route cold again
+    server.clear_state().await.expect("Failed to clear state");
+    let cold_again = timed_search().await;
+    assert!(
+        cold_again >= Duration::from_millis(800),
+        "Request after clear took {cold_again:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_max_connections_queues_excess() {
+    use std::time::Duration;
+
+    let server = TestServer::start_with_args("config.yaml", &["--max-connections", "2"]).await;
+    let address = server.base_url.trim_start_matches("http://").to_string();
+
+    // Two idle connections use up every slot
+    let mut idle = Vec::new();
+    for _ in 0..2 {
+        idle.push(
+            tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Failed to open idle connection"),
+        );
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let client = Client::builder()
+        .timeout(Duration::from_millis(500))
+        .build()
+        .unwrap();
+    let queued = client
+        .get(format!("{}/health", server.base_url))
+        .send()
+        .await;
+    assert!(
+        queued.is_err(),
+        "A connection over the limit should wait, not be served"
+    );
+
+    // Closing a connection frees its slot and the server keeps serving
+    drop(idle);
+    let response = Client::new()
+        .get(format!("{}/health", server.base_url))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .expect("Server should respond once connections close");
+    assert_eq!(response.status(), 200);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_listener() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    let socket_path = std::env::temp_dir().join(format!("nugget-test-{}.sock", std::process::id()));
+    let mut child = Command::new("cargo")
+        .args(["run", "--", "--config", "config.yaml", "--unix-socket"])
+        .arg(&socket_path)
+        .spawn()
+        .expect("Failed to start server");
+
+    let mut raw = String::new();
+    for _ in 0..50 {
+        if let Ok(mut stream) = UnixStream::connect(&socket_path).await {
+            stream
+                .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .expect("Failed to send request");
+            stream
+                .read_to_string(&mut raw)
+                .await
+                .expect("Failed to read response");
+            break;
+        }
+        sleep(Duration::from_millis(200)).await;
+    }
+
+    assert!(
+        raw.starts_with("HTTP/1.1 200 OK"),
+        "Unexpected response: {raw}"
+    );
+    assert!(raw.contains("\"healthy\""));
+
+    // A graceful shutdown removes the socket file
+    let _ = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status();
+    let _ = child.wait();
+    assert!(
+        !socket_path.exists(),
+        "Socket file should be removed on shutdown"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_refuses_to_replace_a_regular_file() {
+    let socket_path =
+        std::env::temp_dir().join(format!("nugget-test-{}.not-a-sock", std::process::id()));
+    std::fs::write(&socket_path, "keep me").expect("Failed to write file");
+
+    let status = Command::new("cargo")
+        .args(["run", "--", "--config", "config.yaml", "--unix-socket"])
+        .arg(&socket_path)
+        .status()
+        .expect("Failed to run server");
+
+    assert!(!status.success(), "Server should refuse to start");
+    assert_eq!(
+        std::fs::read_to_string(&socket_path).expect("File should still exist"),
+        "keep me"
+    );
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+#[tokio::test]
+async fn test_degraded_after_global_request_count() {
+    let server = TestServer::start_with_config("tests/configs/degraded.yaml").await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let client = Client::new();
+    // Requests to any route count towards the threshold
+    for i in 1..=100 {
+        let endpoint = if i % 2 == 0 { "/inventory" } else { "/health" };
+        let response = client
+            .get(format!("{}{}", server.base_url, endpoint))
+            .send()
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.status(), 200, "request {i}");
+    }
+
+    let response = client
+        .get(format!("{}/inventory", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get inventory");
+    assert_eq!(response.status(), 503);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["error"], "Resources exhausted");
+
+    // Clearing state resets the global count
+    server.clear_state().await.expect("Failed to clear state");
+    let response = client
+        .get(format!("{}/inventory", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get inventory");
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn test_stored_references_resolve_on_read() {
+    let server = TestServer::start_with_config("tests/configs/late-references.yaml").await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    // No members exist yet, so the response resolves the reference to an empty list
+    let team = server
+        .post_json("/teams", json!({"name": "Platform"}))
+        .await
+        .expect("Failed to create team");
+    assert_eq!(team["members"], json!([]));
+    let team_id = team["id"].as_str().unwrap().to_string();
+
+    server
+        .post_json("/members", json!({"name": "Ada"}))
+        .await
+        .expect("Failed to create member");
+
+    let team = server
+        .get_json(&format!("/teams/{team_id}"))
+        .await
+        .expect("Failed to get team");
+    assert_eq!(team["members"][0]["name"], "Ada");
+
+    // References inside referenced objects resolve as well
+    let listing = server
+        .get_json("/teams")
+        .await
+        .expect("Failed to list teams");
+    assert_eq!(listing["teams"][0]["name"], "Platform");
+    assert_eq!(listing["teams"][0]["members"][0]["name"], "Ada");
+}
+
+#[tokio::test]
+async fn test_http2_prior_knowledge() {
+    let server = TestServer::start_with_args("config.yaml", &["--http2"]).await;
+
+    let client = Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .expect("Failed to build HTTP/2 client");
+    let response = client
+        .get(format!("{}/health", server.base_url))
+        .send()
+        .await
+        .expect("Failed to send HTTP/2 request");
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.status(), 200);
+
+    // HTTP/1.1 clients are still served
+    let response = Client::new()
+        .get(format!("{}/health", server.base_url))
+        .send()
+        .await
+        .expect("Failed to send HTTP/1.1 request");
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+}
+
+#[tokio::test]
+async fn test_http2_off_by_default() {
+    let server = TestServer::start().await;
+
+    let client = Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .expect("Failed to build HTTP/2 client");
+    let result = client
+        .get(format!("{}/health", server.base_url))
+        .send()
+        .await;
+    assert!(result.is_err(), "HTTP/2 should need --http2");
+}
+
+#[tokio::test]
+async fn test_max_depth_aborts_generation() {
+    let server = TestServer::start_with_args("config.yaml", &["--max-depth", "5"]).await;
+
+    let response = server
+        .get_with_headers("/test/deeply-nested", vec![])
+        .await
+        .expect("Failed to request nested template");
+    assert_eq!(response.status(), 500);
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert!(
+        body["error"]
+            .as_str()
+            .unwrap()
+            .contains("maximum depth of 5"),
+        "Unexpected error: {body}"
+    );
+
+    // Deep payloads are rejected the same way
+    let mut payload = json!("bottom");
+    for _ in 0..10 {
+        payload = json!({"nested": payload});
+    }
+    let response = Client::new()
+        .post(format!("{}/orders", server.base_url))
+        .json(&payload)
+        .send()
+        .await
+        .expect("Failed to post nested payload");
+    assert_eq!(response.status(), 500);
+
+    // Shallow templates are unaffected
+    let health = server
+        .get_json("/health")
+        .await
+        .expect("Failed to get health");
+    assert_eq!(health["status"], "healthy");
+}
+
+#[tokio::test]
+async fn test_global_headers() {
+    let server = TestServer::start_with_config("tests/configs/global-headers.yaml").await;
+
+    for path in ["/health", "/widgets", "/livez", "/missing"] {
+        let response = server
+            .get_with_headers(path, vec![])
+            .await
+            .expect("Failed to send request");
+        assert_eq!(response.headers()["server"], "nugget", "{path}");
+        assert_eq!(response.headers()["x-env"], "staging", "{path}");
+    }
+
+    let response = server
+        .get_with_headers("/preview", vec![])
+        .await
+        .expect("Failed to get preview");
+    assert_eq!(response.headers()["x-env"], "preview");
+    assert_eq!(response.headers()["server"], "nugget");
+}
+
+#[tokio::test]
+async fn test_defaults_file() {
+    let server = TestServer::start_with_config("tests/configs/defaults-file.yaml").await;
+
+    let response = server
+        .post_json("/greetings", json!({}))
+        .await
+        .expect("Failed to post greeting");
+    assert_eq!(response["greeting"], "Hello stranger");
+    assert_eq!(response["region"], "eu-west");
+    // Inline defaults win over the file's
+    assert_eq!(response["currency"], "EUR");
+
+    let response = server
+        .post_json("/greetings", json!({"name": "Ada", "region": "us-east"}))
+        .await
+        .expect("Failed to post greeting");
+    assert_eq!(response["greeting"], "Hello Ada");
+    assert_eq!(response["region"], "us-east");
+}
+
+#[tokio::test]
+async fn test_null_missing_fields_keeps_projections_aligned() {
+    let server = TestServer::start_with_config("tests/configs/null-fields.yaml").await;
+
+    let report = server
+        .get_json("/reports/customers")
+        .await
+        .expect("Failed to get report");
+    assert_eq!(report["ids"], json!(["order-1", "order-2", "order-3"]));
+    assert_eq!(report["customers"], json!(["Alice", null, "Carol"]));
+}
+
+#[tokio::test]
+async fn test_sequence_objects() {
+    let server = TestServer::start_with_config("tests/configs/sequenced.yaml").await;
+
+    let notes = server
+        .get_json("/notes")
+        .await
+        .expect("Failed to list notes");
+    assert_eq!(notes["notes"][0]["_seq"], 1, "Seeds are numbered first");
+
+    let mut created = Vec::new();
+    for text in ["first", "second", "third"] {
+        let note = server
+            .post_json("/notes", json!({"text": text}))
+            .await
+            .expect("Failed to create note");
+        created.push(note);
+    }
+    assert_eq!(created[0]["_seq"], 2);
+    assert_eq!(created[2]["_seq"], 4);
+
+    let notes = server
+        .get_json("/notes")
+        .await
+        .expect("Failed to list notes");
+    let mut notes = notes["notes"].as_array().unwrap().clone();
+    notes.sort_by_key(|note| std::cmp::Reverse(note["_seq"].as_u64().unwrap()));
+    let texts: Vec<&str> = notes.iter().map(|n| n["text"].as_str().unwrap()).collect();
+    assert_eq!(texts, ["third", "second", "first", "Seeded"]);
+
+    // Clearing state restarts the sequence
+    server.clear_state().await.expect("Failed to clear state");
+    let note = server
+        .post_json("/notes", json!({"text": "fresh"}))
+        .await
+        .expect("Failed to create note");
+    assert_eq!(note["_seq"], 1);
+}
+
+#[tokio::test]
+async fn test_openapi_ui() {
+    let server = TestServer::start_with_args("config.yaml", &["--open-api-ui"]).await;
+
+    let spec = server
+        .get_json("/openapi.json")
+        .await
+        .expect("Failed to get OpenAPI spec");
+    assert_eq!(spec["openapi"], "3.0.3");
+
+    let create_order = &spec["paths"]["/orders"]["post"];
+    assert_eq!(create_order["summary"], "POST /orders");
+    assert!(create_order["responses"]["201"]["content"]["application/json"]["example"].is_object());
+
+    let order_items = &spec["paths"]["/inventory/order/{id}/items"]["get"];
+    assert_eq!(order_items["parameters"][0]["name"], "id");
+    assert_eq!(order_items["parameters"][0]["in"], "path");
+
+    let response = Client::new()
+        .get(format!("{}/docs", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get docs page");
+    assert_eq!(response.status(), 200);
+    let page = response.text().await.expect("Failed to read docs page");
+    assert!(page.contains("SwaggerUIBundle"));
+    assert!(page.contains("/openapi.json"));
+}
+
+#[tokio::test]
+async fn test_openapi_expands_optional_segments() {
+    let server = TestServer::start_with_args("lua-test.yaml", &["--open-api-ui"]).await;
+
+    let spec = server
+        .get_json("/openapi.json")
+        .await
+        .expect("Failed to get OpenAPI spec");
+    let paths = spec["paths"].as_object().unwrap();
+    assert!(!paths.contains_key("/catalog/{sku?}"));
+
+    let item = &spec["paths"]["/catalog/{sku}"]["get"];
+    assert_eq!(item["parameters"][0]["name"], "sku");
+    assert_eq!(item["parameters"][0]["required"], true);
+
+    let collection = &spec["paths"]["/catalog"]["get"];
+    assert!(collection.is_object());
+    assert!(collection.get("parameters").is_none());
+}
+
+#[tokio::test]
+async fn test_openapi_ui_disabled_by_default() {
+    let server = TestServer::start().await;
+
+    let response = Client::new()
+        .get(format!("{}/docs", server.base_url))
+        .send()
+        .await
+        .expect("Failed to get docs page");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_request_stats() {
+    let server = TestServer::start_with_args("config.yaml", &["--stats"]).await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let order = server
+        .post_json(
+            "/orders",
+            json!({"customer": "Alice", "items": [], "total": 1}),
+        )
+        .await
+        .expect("Failed to create order");
+    server
+        .post_json(
+            "/orders",
+            json!({"customer": "Bob", "items": [], "total": 2}),
+        )
+        .await
+        .expect("Failed to create order");
+    server
+        .get_json(&format!("/orders/{}", order["id"].as_str().unwrap()))
+        .await
+        .expect("Failed to get order");
+    let response = Client::new()
+        .get(format!("{}/no/such/route", server.base_url))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), 404);
+
+    let stats = server
+        .get_json("/_stats")
+        .await
+        .expect("Failed to get stats");
+    assert_eq!(stats["total_requests"], 4);
+    assert_eq!(stats["routes"]["POST /orders"], 2);
+    assert_eq!(stats["routes"]["GET /orders/{id}"], 1);
+    assert_eq!(stats["objects"]["orders"], 2);
+
+    server.clear_state().await.expect("Failed to clear state");
+    let stats = server
+        .get_json("/_stats")
+        .await
+        .expect("Failed to get stats");
+    assert_eq!(stats["total_requests"], 0);
+    assert_eq!(stats["routes"], json!({}));
+}
+
+#[tokio::test]
+async fn test_expectations_verified_against_history() {
+    let server = TestServer::start_with_args("config.yaml", &["--history"]).await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    for expectation in [
+        json!({"method": "POST", "path": "/orders", "times": 1}),
+        json!({"method": "GET", "path": "/orders/{id}", "at_least": 2}),
+    ] {
+        let response = Client::new()
+            .post(format!("{}/_expect", server.base_url))
+            .json(&expectation)
+            .send()
+            .await
+            .expect("Failed to register expectation");
+        assert_eq!(response.status(), 201);
+    }
+
+    let order = server
+        .post_json(
+            "/orders",
+            json!({"customer": "Alice", "items": [], "total": 5}),
+        )
+        .await
+        .expect("Failed to create order");
+    let order_path = format!("/orders/{}", order["id"].as_str().unwrap());
+    server
+        .get_json(&order_path)
+        .await
+        .expect("Failed to get order");
+
+    let report = server.get_json("/_verify").await.expect("Failed to verify");
+    assert_eq!(report["satisfied"], false);
+    assert_eq!(report["expectations"][0]["satisfied"], true);
+    assert_eq!(report["expectations"][1]["count"], 1);
+    assert_eq!(report["expectations"][1]["satisfied"], false);
+
+    server
+        .get_json(&order_path)
+        .await
+        .expect("Failed to get order");
+    let report = server.get_json("/_verify").await.expect("Failed to verify");
+    assert_eq!(report["satisfied"], true);
+
+    // A second order breaks the exact count
+    server
+        .post_json(
+            "/orders",
+            json!({"customer": "Bob", "items": [], "total": 1}),
+        )
+        .await
+        .expect("Failed to create order");
+    let report = server.get_json("/_verify").await.expect("Failed to verify");
+    assert_eq!(report["expectations"][0]["count"], 2);
+    assert_eq!(report["satisfied"], false);
+}
+
+#[tokio::test]
+async fn test_request_history() {
+    let server =
+        TestServer::start_with_args("config.yaml", &["--history", "--history-size", "2"]).await;
+
+    server.clear_state().await.expect("Failed to clear state");
+
+    for customer in ["First", "Second", "Third"] {
+        server
+            .post_json("/orders", json!({"customer": customer, "total": 1}))
+            .await
+            .expect("Failed to create order");
+    }
+
+    let history = server
+        .get_json("/_history?path=/orders")
+        .await
+        .expect("Failed to get history");
+
+    let requests = history["requests"].as_array().unwrap();
+    assert_eq!(
+        requests.len(),
+        2,
+        "History should be capped at its max size"
+    );
+    assert_eq!(requests[0]["method"], "POST");
+    assert_eq!(requests[0]["path"], "/orders");
+    assert_eq!(
+        requests[0]["body"],
+        json!({"customer": "Second", "total": 1})
+    );
+    assert_eq!(requests[1]["body"]["customer"], "Third");
+    assert_eq!(requests[1]["headers"]["content-type"], "application/json");
+
+    // Parameterised routes are keyed by their pattern
+    server
+        .get_json("/orders/abc")
+        .await
+        .expect("Failed to get order");
+    let history = server
+        .get_json("/_history?path=/orders/{id}")
+        .await
+        .expect("Failed to get history");
+    assert_eq!(history["requests"][0]["path"], "/orders/abc");
+}
+
+#[tokio::test]
+async fn test_replay_recorded_request() {
+    let server = TestServer::start_with_args("config.yaml", &["--history"]).await;
+    server.clear_state().await.expect("Failed to clear state");
+
+    let original = server
+        .post_json("/orders", json!({"customer": "Replayed", "total": 42}))
+        .await
+        .expect("Failed to create order");
+
+    let replayed = server
+        .post_json("/_replay", json!({"path": "/orders", "index": 0}))
+        .await
+        .expect("Failed to replay order");
+    assert_eq!(replayed["customer"], "Replayed");
+    assert_eq!(replayed["total"], 42);
+    assert_ne!(
+        replayed["id"], original["id"],
+        "Replay should create a new order"
+    );
+
+    let id = replayed["id"].as_str().unwrap();
+    let stored = server
+        .get_json(&format!("/orders/{id}"))
+        .await
+        .expect("Failed to get replayed order");
+    assert_eq!(stored["customer"], "Replayed");
+
+    // A full request can be replayed without having been recorded
+    let replayed = server
+        .post_json(
+            "/_replay",
+            json!({"method": "POST", "path": "/orders", "headers": {}, "body": {"customer": "Inline", "total": 1}}),
+        )
+        .await
+        .expect("Failed to replay inline request");
+    assert_eq!(replayed["customer"], "Inline");
+
+    let response = Client::new()
+        .post(format!("{}/_replay", server.base_url))
+        .json(&json!({"path": "/orders", "index": 99}))
+        .send()
+        .await
+        .expect("Failed to replay missing request");
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_quiet_mode_suppresses_output() {
+    let server = TestServer::start_capturing_stdout("config.yaml", &[]).await;
+    server
+        .post_json("/test/variables/uuid", json!({}))
+        .await
+        .expect("Failed to test UUID variables");
+    let output = server.stop();
+    assert!(output.contains("Server running on"), "got: {}", output);
+    assert!(
+        output.contains("UUID type doesn't support 'prefix' parameter"),
+        "got: {}",
+        output
+    );
+
+    let server = TestServer::start_capturing_stdout("config.yaml", &["--quiet"]).await;
+    server
+        .post_json("/test/variables/uuid", json!({}))
+        .await
+        .expect("Failed to test UUID variables");
+    let output = server.stop();
+    assert!(
+        output.is_empty(),
+        "Quiet mode should not log, got: {}",
+        output
+    );
+}
+
+#[tokio::test]
+async fn test_json_startup_banner() {
+    let server =
+        TestServer::start_capturing_stdout("config.yaml", &["--banner", "json", "--quiet"]).await;
+    let port: u64 = server.base_url.rsplit(':').next().unwrap().parse().unwrap();
+    let output = server.stop();
+
+    let line = output.lines().next().expect("No startup line");
+    let startup: Value = serde_json::from_str(line).expect("Startup line should be JSON");
+    assert_eq!(startup["event"], "startup");
+    assert_eq!(startup["port"], port);
+    assert_eq!(startup["config"], "config.yaml");
+    assert!(startup["routes"].as_u64().unwrap() > 0);
+    assert!(startup["version"].as_str().is_some());
+    assert!(startup["worker_threads"].as_u64().unwrap() >= 1);
+
+    let server = TestServer::start_capturing_stdout("config.yaml", &["--banner", "off"]).await;
+    let output = server.stop();
+    assert!(!output.contains("Server running on"), "got: {output}");
+}
+
+#[tokio::test]
+async fn test_worker_threads_flag() {
+    let server = TestServer::start_capturing_stdout(
+        "config.yaml",
+        &["--worker-threads", "2", "--banner", "json", "--quiet"],
+    )
+    .await;
+
+    let requests = (0..8).map(|_| server.get_json("/health"));
+    for result in futures_util::future::join_all(requests).await {
+        assert_eq!(result.expect("Failed to get health")["status"], "healthy");
+    }
+
+    let output = server.stop();
+    let startup: Value =
+        serde_json::from_str(output.lines().next().expect("No startup line")).unwrap();
+    assert_eq!(startup["worker_threads"], 2);
+}
+
+#[tokio::test]
+async fn test_debug_mode_surfaces_warnings() {
+    let server = TestServer::start_with_args("config.yaml", &["--debug"]).await;
+
+    let response = server
+        .post_json("/test/variables/uuid", json!({}))
+        .await
+        .expect("Failed to test UUID variables");
+    assert_eq!(
+        response["_warnings"],
+        json!(["UUID type doesn't support 'prefix' parameter. Ignoring this parameter."])
+    );
+
+    // Routes without warnings are unchanged
+    let response = server
+        .post_json("/test/variables/integer", json!({}))
+        .await
+        .expect("Failed to test integer variables");
+    assert!(response.get("_warnings").is_none());
+}
+
+#[tokio::test]
+async fn test_debug_mode_explains_invalid_json() {
+    let server = TestServer::start_with_args("config.yaml", &["--debug"]).await;
+    let client = Client::new();
+
+    let response = client
+        .post(format!("{}/orders", server.base_url))
+        .header("content-type", "application/json")
+        .body("{\"customer\": \"Ada\",\n \"total\": 5,}")
+        .send()
+        .await
+        .expect("Failed to post malformed order");
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.expect("Failed to parse error");
+    assert_eq!(body["error"], "Invalid JSON body");
+    assert_eq!(body["message"], "trailing comma at line 2 column 13");
+    assert_eq!(body["line"], 2);
+    assert_eq!(body["column"], 13);
+    // The closing brace after the trailing comma
+    assert_eq!(body["offset"], 32);
+    drop(server);
+
+    // Without --debug the 400 carries no details
+    let server = TestServer::start().await;
+    let response = client
+        .post(format!("{}/orders", server.base_url))
+        .header("content-type", "application/json")
+        .body("{oops")
+        .send()
+        .await
+        .expect("Failed to post malformed order");
+    assert_eq!(response.status(), 400);
+    assert!(response.text().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_group_by_count_reference() {
+    let server = TestServer::start_with_config("tests/configs/seed.yaml").await;
+
+    let report = server
+        .get_json("/reports/orders/by-status")
+        .await
+        .expect("Failed to get grouped report");
+
+    assert_eq!(
+        report["orders_by_status"],
+        json!({"pending": 2, "shipped": 1})
+    );
+    assert_eq!(report["users_by_role"], json!({}));
+    // Unknown reducers aren't resolved, even over no objects
+    assert_eq!(
+        report["users_by_role_sum"],
+        "{objects.users group_by role sum}"
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_fallback_for_unmatched_routes() {
+    // A stand-in for the real backend
+    let upstream = axum::Router::new().route(
+        "/real/{name}",
+        axum::routing::any(
+            |method: axum::http::Method,
+             axum::extract::Path(name): axum::extract::Path<String>,
+             body: String| async move {
+                (
+                    axum::http::StatusCode::ACCEPTED,
+                    [("x-upstream", "yes")],
+                    axum::Json(json!({"method": method.as_str(), "name": name, "body": body})),
+                )
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind upstream");
+    let upstream_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+    let config_path = std::env::temp_dir().join("nugget-proxy-fallback.yaml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "proxy_fallback: \"http://{}\"\nroutes:\n  - path: /health\n    method: GET\n    response:\n      status: 200\n      body:\n        status: mocked\n",
+            upstream_addr
+        ),
+    )
+    .expect("Failed to write config");
+
+    let server = TestServer::start_with_config(config_path.to_str().unwrap()).await;
+
+    // Mocked routes take precedence
+    let health = server
+        .get_json("/health")
+        .await
+        .expect("Failed to get health");
+    assert_eq!(health["status"], "mocked");
+
+    let response = Client::new()
+        .post(format!("{}/real/widgets?page=2", server.base_url))
+        .body("raw body")
+        .send()
+        .await
+        .expect("Failed to call proxied route");
+    assert_eq!(response.status(), 202);
+    assert_eq!(response.headers()["x-upstream"], "yes");
+
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["method"], "POST");
+    assert_eq!(body["name"], "widgets");
+    assert_eq!(body["body"], "raw body");
+}
+
+#[tokio::test]
+async fn test_liveness_and_readiness_probes() {
+    let server = TestServer::start_with_config("lua-test.yaml").await;
+
+    let livez = server
+        .get_with_headers("/livez", vec![])
+        .await
+        .expect("Failed to probe livez");
+    assert_eq!(livez.status(), 200);
+
+    let readyz = server
+        .get_with_headers("/readyz", vec![])
+        .await
+        .expect("Failed to probe readyz");
+    assert_eq!(readyz.status(), 200);
+    let body: Value = readyz.json().await.expect("Failed to parse JSON");
+    assert_eq!(body["status"], "ready");
+}
+
+#[tokio::test]
+async fn test_configured_routes_replace_built_ins() {
+    let server = TestServer::start_with_config("tests/configs/built-in-overrides.yaml").await;
+
+    let livez = server
+        .get_json("/livez")
+        .await
+        .expect("Failed to probe livez");
+    assert_eq!(livez, json!({"probe": "configured"}));
+
+    // A configured route under /_echo takes over the whole echo endpoint
+    let response = Client::new()
+        .put(format!("{}/_echo/widgets", server.base_url))
+        .json(&json!({}))
+        .send()
+        .await
+        .expect("Failed to put");
+    let body: Value = response.json().await.expect("Failed to parse JSON");
+    assert_eq!(body, json!({"echoed": false, "name": "widgets"}));
+
+    let preview = server
+        .post_json("/_preview", json!({"route_path": "/livez"}))
+        .await
+        .expect("Failed to post preview");
+    assert_eq!(preview, json!({"preview": "configured"}));
+
+    // Built-ins without a configured route are still served
+    let readyz = server
+        .get_with_headers("/readyz", vec![])
+        .await
+        .expect("Failed to probe readyz");
+    assert_eq!(readyz.status(), 200);
+}
+
+#[tokio::test]
+async fn test_readiness_waits_for_startup() {
+    let config: nugget::Config =
+        serde_json::from_value(json!({ "routes": [] })).expect("Failed to build config");
+    let state = nugget::AppState::new(config);
+    let app = nugget::build_router(state.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind listener");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let client = Client::new();
+    let livez = client
+        .get(format!("http://{}/livez", addr))
+        .send()
+        .await
+        .expect("Failed to probe livez");
+    assert_eq!(livez.status(), 200);
+
+    let readyz = client
+        .get(format!("http://{}/readyz", addr))
+        .send()
+        .await
+        .expect("Failed to probe readyz");
+    assert_eq!(readyz.status(), 503);
+
+    state.mark_ready();
+    let readyz = client
+        .get(format!("http://{}/readyz", addr))
+        .send()
+        .await
+        .expect("Failed to probe readyz");
+    assert_eq!(readyz.status(), 200);
+}
+
+#[tokio::test]
+async fn test_response_charset_transcoding() {
+    let server = TestServer::start().await;
+
+    let response = server
+        .get_with_headers("/i18n/latin1", vec![])
+        .await
+        .expect("Failed to get latin1 route");
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/json; charset=ISO-8859-1"
+    );
+
+    let bytes = response.bytes().await.expect("Failed to read body");
+    assert_eq!(&bytes[..], b"{\"name\":\"Jos\xe9 M\xfcller\"}");
+}